@@ -0,0 +1,14 @@
+/// 子を取り出せる木の節
+pub trait IntoChildren: Sized {
+  /// 節を解放し、子を取り出す
+  fn into_children(self) -> Vec<Self>;
+}
+
+/// 深い木でもスタックがあふれないように、子孫を明示的なスタックに移してから一つずつ解放する
+/// 左結合の長い式のような深い木は、そのまま解放すると再帰的に解放されてスタックがあふれる
+pub fn drop_deep<T: IntoChildren>(root: T) {
+  let mut stack = vec![root];
+  while let Some(node) = stack.pop() {
+    stack.extend(node.into_children())
+  }
+}
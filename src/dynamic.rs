@@ -1,4 +1,4 @@
-use crate::{ColumnConfig, Context};
+use crate::{deep_drop::IntoChildren, ColumnConfig, Context};
 use core::panic;
 use std::{collections::HashMap, hash::Hash};
 
@@ -52,6 +52,7 @@ pub enum Rule {
   Column(Option<Tag>, Vec<(RuleWithComment, ColumnConfig)>),
 }
 
+/// 非常に深い木はそのまま解放すると再帰的に解放されてスタックがあふれるので、`deep_drop::drop_deep`で解放する
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct RuleWithComment {
   pub before_comments: Vec<String>,
@@ -59,14 +60,25 @@ pub struct RuleWithComment {
   pub after_comment: Option<String>,
 }
 
-fn with_comment(r: &Rule) -> RuleWithComment {
+fn with_comment(rule: Rule) -> RuleWithComment {
   RuleWithComment {
     before_comments: vec![],
-    rule: r.clone(),
+    rule,
     after_comment: None,
   }
 }
 
+impl IntoChildren for RuleWithComment {
+  fn into_children(self) -> Vec<Self> {
+    match self.rule {
+      Rule::AST(child) | Rule::Paren(_, _, child, _) => vec![*child],
+      Rule::List(_, _, lst) => lst,
+      Rule::Column(_, lst) => lst.into_iter().map(|(child, _)| child).collect(),
+      Rule::Unconfirmed(_) | Rule::Raw(_) => vec![],
+    }
+  }
+}
+
 pub type FormattedRuleStack = Vec<OpenRule>;
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -244,108 +256,129 @@ pub fn flat_listedrule(
 
 /// hashmapを使って分散しているルールを一つのリストにつぶされたことを前提に木構造化する
 /// 元のリストをかなり信頼してよく、チェックもあまり行わず、パニックしてよい
+/// 深くネストしたリストでもスタックがあふれないように、組み立て途中のルールを明示的なスタックに積んでいく
+/// 組み立てたルールが非常に深い場合は、呼び出し側で`deep_drop::drop_deep`を使って解放する
 pub fn listedrule_to_rule(
   listed_rules: &[ListedRule],
   count: usize,
 ) -> (RuleWithComment, Option<ColumnConfig>, usize) {
-  match listed_rules.get(count) {
-    Some(ListedRule::Raw(str)) => (with_comment(&Rule::Raw(str.to_string())), None, count + 1),
-    Some(ListedRule::Unconfirmed(tag)) => (
-      with_comment(&Rule::Unconfirmed(tag.to_string())),
-      None,
-      count + 1,
-    ),
-    Some(ListedRule::Open(OpenRule::List(_, join))) => {
-      let mut v = vec![];
-      let mut c = count + 1;
-      loop {
-        match listed_rules.get(c) {
-          Some(ListedRule::Close(CloseRule::List)) => break,
-          _ => {
-            let (rule_with_comment, _, new_c) = listedrule_to_rule(listed_rules, c);
-            v.push(rule_with_comment);
-            c = new_c;
-          }
+  /// 閉じる要素を待っている組み立て途中のルール
+  enum Frame<'a> {
+    List(&'a str, Vec<RuleWithComment>),
+    Paren(&'a str, &'a BeforeComments),
+    Contents(&'a BeforeComments),
+    ColumnContents(&'a ColumnConfig, &'a BeforeComments),
+    Column(Vec<(RuleWithComment, ColumnConfig)>),
+  }
+  let mut stack = vec![];
+  let mut count = count;
+  loop {
+    // 一つの要素を読み、完成したルールがあれば取り出す
+    let (mut rule_with_comment, mut column_config_opt) =
+      match (stack.last(), listed_rules.get(count)) {
+        (Some(Frame::List(..)), Some(ListedRule::Close(CloseRule::List))) => {
+          let Some(Frame::List(join, v)) = stack.pop() else {
+            unreachable!()
+          };
+          (with_comment(Rule::List(None, join.to_string(), v)), None)
         }
-      }
-      let rule = Rule::List(None, join.to_string(), v);
-      (with_comment(&rule), None, c + 1)
-    }
-    Some(ListedRule::Open(OpenRule::Paren(_, open_str, before_comments))) => {
-      let (rule_with_comment, _, count) = listedrule_to_rule(listed_rules, count + 1);
-      match listed_rules.get(count) {
-        Some(ListedRule::Close(CloseRule::Paren(close_str, after_comment))) => {
-          let rule_with_comment = RuleWithComment {
-            before_comments: before_comments.clone(),
-            rule: rule_with_comment.rule,
-            after_comment: after_comment.clone(),
+        (Some(Frame::Column(..)), Some(ListedRule::Close(CloseRule::Column))) => {
+          let Some(Frame::Column(v)) = stack.pop() else {
+            unreachable!()
           };
-          let rule = Rule::Paren(
-            None,
-            open_str.to_string(),
-            Box::new(rule_with_comment),
-            close_str.clone(),
-          );
-          (with_comment(&rule), None, count + 1)
+          (with_comment(Rule::Column(None, v)), None)
+        }
+        (_, Some(ListedRule::Raw(str))) => (with_comment(Rule::Raw(str.to_string())), None),
+        (_, Some(ListedRule::Unconfirmed(tag))) => {
+          (with_comment(Rule::Unconfirmed(tag.to_string())), None)
+        }
+        (_, Some(ListedRule::Open(open_rule))) => {
+          stack.push(match open_rule {
+            OpenRule::List(_, join) => Frame::List(join, vec![]),
+            OpenRule::Paren(_, open_str, before_comments) => {
+              Frame::Paren(open_str, before_comments)
+            }
+            OpenRule::Contents(before_comments) => Frame::Contents(before_comments),
+            OpenRule::ColumnContents(column_config, before_comments) => {
+              Frame::ColumnContents(column_config, before_comments)
+            }
+            OpenRule::Column(_) => Frame::Column(vec![]),
+          });
+          count += 1;
+          continue;
         }
         _ => unreachable!(),
-      }
-    }
-    Some(ListedRule::Open(OpenRule::Contents(before_comments))) => {
-      let (rule_with_comment, _, count) = listedrule_to_rule(listed_rules, count + 1);
-      match listed_rules.get(count) {
-        Some(ListedRule::Close(CloseRule::Contents(after_comment))) => {
-          let rule = if before_comments.is_empty() && after_comment.is_none() {
-            rule_with_comment.rule
-          } else {
-            let rule_with_comment = RuleWithComment {
+      };
+    count += 1;
+    // 完成したルールを親に渡す
+    // 中身を一つだけ持つルールは閉じる要素を読んで完成させ、さらにその親に渡す
+    loop {
+      match stack.pop() {
+        None => return (rule_with_comment, column_config_opt, count),
+        Some(Frame::List(join, mut v)) => {
+          v.push(rule_with_comment);
+          stack.push(Frame::List(join, v));
+          break;
+        }
+        Some(Frame::Column(mut v)) => {
+          match column_config_opt {
+            Some(config) => v.push((rule_with_comment, config)),
+            None => panic!(),
+          }
+          stack.push(Frame::Column(v));
+          break;
+        }
+        Some(Frame::Paren(open_str, before_comments)) => match listed_rules.get(count) {
+          Some(ListedRule::Close(CloseRule::Paren(close_str, after_comment))) => {
+            let rule_with_comment_inner = RuleWithComment {
               before_comments: before_comments.clone(),
               rule: rule_with_comment.rule,
               after_comment: after_comment.clone(),
             };
-            Rule::AST(Box::new(rule_with_comment))
-          };
-          (with_comment(&rule), None, count + 1)
-        }
-        _ => unreachable!(),
-      }
-    }
-    Some(ListedRule::Open(OpenRule::ColumnContents(column_config, before_comments))) => {
-      let (rule_with_comment, _, count) = listedrule_to_rule(listed_rules, count + 1);
-      match listed_rules.get(count) {
-        Some(ListedRule::Close(CloseRule::ColumnContents(after_comment))) => {
-          let rule_with_comment = RuleWithComment {
-            before_comments: before_comments.clone(),
-            rule: rule_with_comment.rule,
-            after_comment: after_comment.clone(),
-          };
-          (rule_with_comment, Some(column_config.clone()), count + 1)
-        }
-        _ => unreachable!(),
-      }
-    }
-    Some(ListedRule::Open(OpenRule::Column(_))) => {
-      let mut v = vec![];
-      let mut c = count + 1;
-      loop {
-        match listed_rules.get(c) {
-          Some(ListedRule::Close(CloseRule::Column)) => break,
-          _ => {
-            let (rule_with_comment, column_config_opt, new_c) = listedrule_to_rule(listed_rules, c);
-            match column_config_opt {
-              Some(config) => {
-                v.push((rule_with_comment, config));
-                c = new_c;
-              }
-              None => panic!(),
+            let rule = Rule::Paren(
+              None,
+              open_str.to_string(),
+              Box::new(rule_with_comment_inner),
+              close_str.clone(),
+            );
+            rule_with_comment = with_comment(rule);
+            column_config_opt = None;
+          }
+          _ => unreachable!(),
+        },
+        Some(Frame::Contents(before_comments)) => match listed_rules.get(count) {
+          Some(ListedRule::Close(CloseRule::Contents(after_comment))) => {
+            let rule = if before_comments.is_empty() && after_comment.is_none() {
+              rule_with_comment.rule
+            } else {
+              let rule_with_comment_inner = RuleWithComment {
+                before_comments: before_comments.clone(),
+                rule: rule_with_comment.rule,
+                after_comment: after_comment.clone(),
+              };
+              Rule::AST(Box::new(rule_with_comment_inner))
+            };
+            rule_with_comment = with_comment(rule);
+            column_config_opt = None;
+          }
+          _ => unreachable!(),
+        },
+        Some(Frame::ColumnContents(column_config, before_comments)) => {
+          match listed_rules.get(count) {
+            Some(ListedRule::Close(CloseRule::ColumnContents(after_comment))) => {
+              rule_with_comment = RuleWithComment {
+                before_comments: before_comments.clone(),
+                rule: rule_with_comment.rule,
+                after_comment: after_comment.clone(),
+              };
+              column_config_opt = Some(column_config.clone());
             }
+            _ => unreachable!(),
           }
         }
       }
-      let rule = Rule::Column(None, v);
-      (with_comment(&rule), None, c + 1)
+      count += 1;
     }
-    _ => unreachable!(),
   }
 }
 
@@ -422,6 +455,7 @@ impl Data {
   /// 1. 更新できるデータセット
   /// 2. 今作業しているデータのタグの名前
   /// 3. 値を確定させたい対象のタグの名前
+  ///
   /// となっている
   fn confirmed_with_tag(
     &mut self,
@@ -441,7 +475,7 @@ impl Data {
           ListedRule::Unconfirmed(unconfirmed_tag_name)
             if unconfirmed_tag_name == target_tag_name =>
           {
-            if self.tag_data.get(target_tag_name).is_none() {
+            if !self.tag_data.contains_key(target_tag_name) {
               panic!()
             }
             new_rules.push(ListedRule::Link(target_tag_name.to_string()));
@@ -450,7 +484,7 @@ impl Data {
           ListedRule::Open(OpenRule::Paren(Some(open_tag_name), open_str, comments))
             if open_tag_name == target_tag_name =>
           {
-            if self.tag_data.get(target_tag_name).is_none() {
+            if !self.tag_data.contains_key(target_tag_name) {
               panic!()
             }
             new_rules.push(ListedRule::Open(OpenRule::Paren(
@@ -464,7 +498,7 @@ impl Data {
           ListedRule::Open(OpenRule::List(Some(open_tag_name), join))
             if open_tag_name == target_tag_name =>
           {
-            if self.tag_data.get(target_tag_name).is_none() {
+            if !self.tag_data.contains_key(target_tag_name) {
               panic!()
             }
             new_rules.push(ListedRule::Open(OpenRule::List(None, join.clone())));
@@ -474,7 +508,7 @@ impl Data {
           ListedRule::Open(OpenRule::Column(Some(open_tag_name)))
             if open_tag_name == target_tag_name =>
           {
-            if self.tag_data.get(target_tag_name).is_none() {
+            if !self.tag_data.contains_key(target_tag_name) {
               panic!()
             }
             new_rules.push(ListedRule::Open(OpenRule::Column(None)));
@@ -484,7 +518,7 @@ impl Data {
           // 目標とするタグ名ではなかったため、リンク先のルールを見に行き、
           // そこに目標があったら終了
           ListedRule::Unconfirmed(unconfirmed_tag_name)
-            if self.tag_data.get(unconfirmed_tag_name).is_some() =>
+            if self.tag_data.contains_key(unconfirmed_tag_name) =>
           {
             if let Some(unconfirmed_internal_rules) = self.tag_data.get(unconfirmed_tag_name) {
               let new_internal_rule_opt =
@@ -498,7 +532,7 @@ impl Data {
               }
             }
           }
-          ListedRule::Link(linked_tag_name) if self.tag_data.get(linked_tag_name).is_some() => {
+          ListedRule::Link(linked_tag_name) if self.tag_data.contains_key(linked_tag_name) => {
             if let Some(linked_internal_rules) = self.tag_data.get(linked_tag_name) {
               let new_internal_rule_opt =
                 self.confirmed_with_tag(&linked_internal_rules.clone(), target_tag_name);
@@ -512,7 +546,7 @@ impl Data {
             }
           }
           ListedRule::Open(OpenRule::Paren(Some(linked_tag_name), open_str, comments))
-            if self.tag_data.get(linked_tag_name).is_some() =>
+            if self.tag_data.contains_key(linked_tag_name) =>
           {
             if let Some(linked_internal_rules) = self.tag_data.get(linked_tag_name) {
               let new_internal_rule_opt =
@@ -531,7 +565,7 @@ impl Data {
             }
           }
           ListedRule::Open(OpenRule::List(Some(linked_tag_name), join))
-            if self.tag_data.get(linked_tag_name).is_some() =>
+            if self.tag_data.contains_key(linked_tag_name) =>
           {
             if let Some(linked_internal_rules) = self.tag_data.get(linked_tag_name) {
               let new_internal_rule_opt =
@@ -549,7 +583,7 @@ impl Data {
             }
           }
           ListedRule::Open(OpenRule::Column(Some(linked_tag_name)))
-            if self.tag_data.get(linked_tag_name).is_some() =>
+            if self.tag_data.contains_key(linked_tag_name) =>
           {
             if let Some(linked_internal_rules) = self.tag_data.get(linked_tag_name) {
               let new_internal_rule_opt =
@@ -580,11 +614,11 @@ impl Data {
   }
 
   /// コードフォーマット
-  pub fn format(&mut self, ctx: Context) -> Vec<String> {
-    let mut code_lst = vec![];
+  pub fn format(&mut self, _ctx: Context) -> Vec<String> {
+    let code_lst = vec![];
     let root = self.tag_data.get("root").unwrap();
     let root_rules = root.clone().rules;
-    for root_rule in root_rules.iter() {}
+    for _root_rule in root_rules.iter() {}
     code_lst
   }
 }
@@ -1,39 +1,230 @@
+pub mod deep_drop;
 pub mod dynamic;
 pub mod tree;
+pub mod width;
+
+use tree::Comment;
 
 #[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct ColumnConfig {
+  /// トークンの直後で改行するかどうか
+  /// `Some(true)`であれば必ず改行し、`Some(false)`であれば改行しない
+  /// `None`であれば改行してもよい
   pub is_break: Option<bool>,
+  /// `is_break`が`None`のとき、トークンの直後で改行した場合のペナルティ
+  /// `ColumnLayout::Optimal`は、ペナルティとはみ出した文字数の合計が小さくなるように改行位置を選ぶ
+  pub penalty: usize,
   /// トークン間に入れるスペースの数
   /// `None`であればデフォルトは1つの空白を入れる
   /// 0であれば空白無しで結合する
   pub space_size: Option<usize>,
+  /// `Rule::Table`の中で、各行の同じ位置にあるセルの開始位置を揃えるかどうか
+  pub is_aligned: bool,
 }
 
 impl ColumnConfig {
-  pub fn set_is_break(&self, penalty: Option<bool>) -> Self {
+  pub fn set_is_break(&self, is_break: Option<bool>) -> Self {
+    ColumnConfig { is_break, ..*self }
+  }
+  pub fn set_penalty(&self, penalty: usize) -> Self {
+    ColumnConfig { penalty, ..*self }
+  }
+  pub fn set_space_size(&self, size: usize) -> Self {
     ColumnConfig {
-      is_break: penalty,
+      space_size: Some(size),
       ..*self
     }
   }
-  pub fn set_space_size(&self, size: usize) -> Self {
+  pub fn set_is_aligned(&self, is_aligned: bool) -> Self {
     ColumnConfig {
-      space_size: Some(size),
+      is_aligned,
       ..*self
     }
   }
 }
 
+/// 複数行になったリストの最後の要素の後ろに区切り文字を入れるかどうか
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TrailingSeparator {
+  /// 入れない
+  #[default]
+  Never,
+  /// 一行のときも複数行のときも入れる
+  Always,
+  /// 複数行になったときだけ入れる
+  Multiline,
+}
+
+/// 複数行になったリストの区切り文字を置く位置
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SeparatorPosition {
+  /// 各要素の行末に置く
+  #[default]
+  Trailing,
+  /// 二つ目以降の要素の行頭に置く
+  /// この場合`TrailingSeparator`は無視される
+  Leading,
+}
+
+/// 一行に収まらなかったリストの並べ方
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ListLayout {
+  /// 一行に一つずつ要素を並べる
+  #[default]
+  Vertical,
+  /// 一行の長さの制限まで、一行にできるだけ多くの要素を詰め込む
+  /// 前にコメントがある要素と複数行になる要素の前では必ず改行する
+  Fill,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ListConfig {
+  pub layout: ListLayout,
+  pub trailing_separator: TrailingSeparator,
+  pub separator_position: SeparatorPosition,
+  /// 区切り文字の前に入れるスペースの数
+  /// `None`であればデフォルトは空白を入れない
+  pub space_before_separator: Option<usize>,
+  /// 区切り文字の後に入れるスペースの数
+  /// `None`であればデフォルトは1つの空白を入れる
+  /// 行末に来た区切り文字の後には入れない
+  pub space_after_separator: Option<usize>,
+  /// 複数行になったとき、連続する行の直後のコメントの開始位置を揃えるかどうか
+  /// 揃えると一行の長さの制限を超えてしまう行は揃えない
+  pub is_comment_aligned: bool,
+  /// 前のコメントが一行のブロックコメントだけである要素を、コメントを要素の直前に置いて同じ行に並べるかどうか
+  /// そのような要素があってもリストは必ずしも複数行に分割されない
+  pub is_block_comment_inlined: bool,
+}
+
+impl ListConfig {
+  pub fn set_layout(&self, layout: ListLayout) -> Self {
+    ListConfig { layout, ..*self }
+  }
+  pub fn set_trailing_separator(&self, trailing_separator: TrailingSeparator) -> Self {
+    ListConfig {
+      trailing_separator,
+      ..*self
+    }
+  }
+  pub fn set_separator_position(&self, separator_position: SeparatorPosition) -> Self {
+    ListConfig {
+      separator_position,
+      ..*self
+    }
+  }
+  pub fn set_space_before_separator(&self, size: usize) -> Self {
+    ListConfig {
+      space_before_separator: Some(size),
+      ..*self
+    }
+  }
+  pub fn set_space_after_separator(&self, size: usize) -> Self {
+    ListConfig {
+      space_after_separator: Some(size),
+      ..*self
+    }
+  }
+  pub fn set_is_comment_aligned(&self, is_comment_aligned: bool) -> Self {
+    ListConfig {
+      is_comment_aligned,
+      ..*self
+    }
+  }
+  pub fn set_is_block_comment_inlined(&self, is_block_comment_inlined: bool) -> Self {
+    ListConfig {
+      is_block_comment_inlined,
+      ..*self
+    }
+  }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ParenConfig {
+  /// 複数行になったとき、中身を一段インデントする代わりに開き括弧の直後の位置に揃えるかどうか
+  /// 揃える場合、中身は開き括弧と同じ行から始まり、閉じ括弧は最後の行の末尾に置く
+  pub is_aligned: bool,
+  /// 開き括弧の直後に付けるコメント
+  /// 開き括弧と同じ行の末尾に置き、`Paren`は必ず複数行に分割される
+  pub open_comment: Option<Comment>,
+  /// 閉じ括弧の直前に付けるコメント
+  /// 中身の後ろの独立した行に置き、`Paren`は必ず複数行に分割される
+  pub close_comment: Option<Comment>,
+}
+
+impl ParenConfig {
+  pub fn set_is_aligned(&self, is_aligned: bool) -> Self {
+    ParenConfig {
+      is_aligned,
+      ..self.clone()
+    }
+  }
+  pub fn set_open_comment(&self, comment: Comment) -> Self {
+    ParenConfig {
+      open_comment: Some(comment),
+      ..self.clone()
+    }
+  }
+  pub fn set_close_comment(&self, comment: Comment) -> Self {
+    ParenConfig {
+      close_comment: Some(comment),
+      ..self.clone()
+    }
+  }
+}
+
+/// `Rule::Column`の改行位置を決めるアルゴリズム
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ColumnLayout {
+  /// 前から順に見て、その場その場ではみ出しが少ない方を選ぶ
+  #[default]
+  Greedy,
+  /// 改行位置の組み合わせ全体を見て、コストが最小になるものを選ぶ
+  /// コストは「はみ出した文字数と改行のペナルティの合計」「行数」「行末の余白の二乗和」の順で比較する
+  Optimal,
+  /// 全体が一行に収まらなければ、`is_break`が`None`のポイントですべて改行する
+  Consistent,
+}
+
+/// インデントに使う文字
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IndentStyle {
+  /// `Context::tab_spaces`個の空白でインデントする
+  #[default]
+  Space,
+  /// 一段につき一つのタブ文字でインデントする
+  /// `width`はタブ文字一つの見た目の幅で、行の長さの計算に使う
+  Tab { width: usize },
+}
+
 #[derive(Clone)]
 pub struct Context<'a> {
   pub depth: usize,
+  /// インデントとは別に行頭に追加される幅
+  /// 開き括弧の直後に揃えた`Paren`の中身で使う
+  pub offset: usize,
   pub tab_spaces: usize,
+  pub indent_style: IndentStyle,
+  /// `Column`が複数行に分割されたとき、二行目以降の行に付ける空白の数
+  pub continuation_indent: usize,
   pub line_width: usize,
   pub break_str: String,
+  /// 連続する空行の最大数
+  pub max_blank_lines: usize,
   pub list_join_str: Option<String>,
+  /// 直近の`List`・`Column`・`Paren`が複数行に分割されたかどうか
+  /// `Rule::IfBreak`の出力の切り替えに使う
+  pub is_broken: bool,
+  pub column_layout: ColumnLayout,
+  /// East Asian WidthがAmbiguousの文字の表示幅（1か2）
+  pub ambiguous_width: usize,
+  /// 要素の前のコメントと独立した行に置くコメントを、一行の長さの制限に収まるように単語の区切りで折り返すかどうか
+  /// 行末に置くコメントは折り返さない
+  pub is_comment_reflowed: bool,
   pub oneline_comment_format: &'a dyn Fn(String) -> String,
   pub block_comment_format: &'a dyn Fn(Context, Vec<String>) -> Vec<String>,
+  /// 連続するドキュメントコメントの行をまとめてフォーマットする
+  pub doc_comment_format: &'a dyn Fn(Context, Vec<String>) -> Vec<String>,
 }
 
 impl<'a> Context<'a> {
@@ -43,12 +234,42 @@ impl<'a> Context<'a> {
       ..self.clone()
     }
   }
+  fn reset_depth(&self) -> Self {
+    Context {
+      depth: 0,
+      offset: 0,
+      ..self.clone()
+    }
+  }
+  fn add_offset(&self, offset: usize) -> Self {
+    Context {
+      offset: self.offset + offset,
+      ..self.clone()
+    }
+  }
   fn indent(&self) -> String {
-    " ".repeat(self.tab_spaces)
+    match self.indent_style {
+      IndentStyle::Space => " ".repeat(self.tab_spaces),
+      IndentStyle::Tab { .. } => "\t".to_string(),
+    }
+  }
+  /// 一段分のインデントの見た目の幅
+  fn indent_width(&self) -> usize {
+    match self.indent_style {
+      IndentStyle::Space => self.tab_spaces,
+      IndentStyle::Tab { width } => width,
+    }
+  }
+  fn width(&self, s: &str) -> usize {
+    width::str_width(s, self.ambiguous_width)
   }
   fn len_max(&self) -> usize {
-    let indent_len = self.tab_spaces * self.depth;
-    self.line_width - indent_len
+    let indent_len = self.indent_width() * self.depth + self.offset;
+    self.line_width.saturating_sub(indent_len)
+  }
+  /// `Column`が分割されてできた二行目以降の行の長さの制限
+  fn continuation_len_max(&self) -> usize {
+    self.len_max().saturating_sub(self.continuation_indent)
   }
   fn set_list_join_str(&self, j_opt: Option<String>) -> Self {
     Context {
@@ -56,4 +277,16 @@ impl<'a> Context<'a> {
       ..self.clone()
     }
   }
+  fn set_is_broken(&self, is_broken: bool) -> Self {
+    Context {
+      is_broken,
+      ..self.clone()
+    }
+  }
+  fn set_continuation_indent(&self, continuation_indent: usize) -> Self {
+    Context {
+      continuation_indent,
+      ..self.clone()
+    }
+  }
 }
@@ -1,27 +1,174 @@
 mod format;
 
-use crate::{ColumnConfig, Context};
+use crate::{deep_drop::IntoChildren, ColumnConfig, Context, ListConfig, ParenConfig};
 
 #[derive(Clone, Debug)]
 pub enum Rule {
   AST(Box<RuleWithComment>),
   Raw(String),
-  List(String, Vec<RuleWithComment>),
-  Paren(String, Box<RuleWithComment>, String),
+  /// 改行を含む文字列をそのまま出力する
+  /// 二行目以降にはインデントを付けず、幅は行ごとに数える
+  Verbatim(String),
+  List(String, Vec<RuleWithComment>, ListConfig),
+  Paren(String, Box<RuleWithComment>, String, ParenConfig),
   Column(Vec<(RuleWithComment, ColumnConfig)>),
+  /// 各要素を一行ずつ並べ、`Column`である要素は`is_aligned`が設定されたセルの開始位置を揃える
+  Table(Vec<RuleWithComment>),
+  /// 直近の`List`・`Column`・`Paren`が複数行に分割されたときは一つ目を、一行に収まったときは二つ目を出力する
+  IfBreak(Box<RuleWithComment>, Box<RuleWithComment>),
+  /// 必ず改行する
+  /// 囲んでいる`List`と`Column`は必ず複数行に分割される
+  HardLine,
+  /// 必ず改行し、指定された数の空行を入れる
+  /// 空行の数は`Context::max_blank_lines`を上限とする
+  BlankLine(usize),
+  /// 囲んでいる構造のインデントに関わらず、行頭から出力する
+  /// 前後では必ず改行し、`List`の区切り文字も入れない
+  Dedent(Box<RuleWithComment>),
 }
 
+/// コメントの種類
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CommentKind {
+  /// 行末までのコメント
+  /// 一行ずつ`Context::oneline_comment_format`でフォーマットする
+  #[default]
+  Line,
+  /// 範囲を囲むコメント
+  /// 一つのコメントごとに`Context::block_comment_format`でフォーマットする
+  Block,
+  /// ドキュメントコメント
+  /// 連続するものをまとめて`Context::doc_comment_format`でフォーマットする
+  Doc,
+}
+
+/// 直後のコメントを置く位置
+/// 前のコメントは常に要素の前の独立した行に置く
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CommentPlacement {
+  /// 要素の最後の行の末尾に置く
+  #[default]
+  EndOfLine,
+  /// 要素の次の独立した行に置く
+  /// 囲んでいる`List`・`Column`・`Paren`は必ず複数行に分割される
+  OwnLine,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Comment {
+  /// コメント記号を含まない本文
+  /// 改行を含む場合は行ごとにフォーマットされる
+  pub text: String,
+  pub kind: CommentKind,
+  pub placement: CommentPlacement,
+}
+
+impl Comment {
+  pub fn line(text: &str) -> Self {
+    Comment {
+      text: text.to_string(),
+      ..Default::default()
+    }
+  }
+  pub fn block(text: &str) -> Self {
+    Comment {
+      text: text.to_string(),
+      kind: CommentKind::Block,
+      ..Default::default()
+    }
+  }
+  pub fn doc(text: &str) -> Self {
+    Comment {
+      text: text.to_string(),
+      kind: CommentKind::Doc,
+      ..Default::default()
+    }
+  }
+  pub fn set_placement(&self, placement: CommentPlacement) -> Self {
+    Comment {
+      placement,
+      ..self.clone()
+    }
+  }
+}
+
+/// 非常に深い木はそのまま解放すると再帰的に解放されてスタックがあふれるので、`deep_drop::drop_deep`で解放する
 #[derive(Clone, Debug)]
 pub struct RuleWithComment {
-  pub before_comments: Vec<String>,
+  pub before_comments: Vec<Comment>,
   pub rule: Rule,
-  pub after_comment: Option<String>,
+  pub after_comment: Option<Comment>,
+}
+
+impl IntoChildren for RuleWithComment {
+  fn into_children(self) -> Vec<Self> {
+    match self.rule {
+      Rule::AST(child) | Rule::Paren(_, child, _, _) | Rule::Dedent(child) => vec![*child],
+      Rule::List(_, lst, _) | Rule::Table(lst) => lst,
+      Rule::Column(lst) => lst.into_iter().map(|(child, _)| child).collect(),
+      Rule::IfBreak(break_rule_with_comment, flat_rule_with_comment) => {
+        vec![*break_rule_with_comment, *flat_rule_with_comment]
+      }
+      Rule::Raw(_) | Rule::Verbatim(_) | Rule::HardLine | Rule::BlankLine(_) => vec![],
+    }
+  }
 }
 
 pub fn code_format(ctx: &Context, rule_with_comment: &RuleWithComment) -> String {
-  format::code_format(ctx, rule_with_comment)
-    .0
-    .join(&ctx.break_str)
+  let mut code = String::new();
+  // `String`への書き込みは失敗しない
+  code_format_to_fmt(ctx, rule_with_comment, &mut code).unwrap();
+  code
+}
+
+/// フォーマットした結果を`std::io::Write`に書き出す
+/// 行は`Context::break_str`で区切り、最後の行の後ろには何も書かない
+/// 最上位の`List`・`Table`は要素ごとにフォーマットし、確定した行から書き出していく
+/// 最上位がそれ以外のルールであれば、すべての行をフォーマットし終えてから書き出す
+pub fn code_format_to_io<W: std::io::Write>(
+  ctx: &Context,
+  rule_with_comment: &RuleWithComment,
+  writer: &mut W,
+) -> std::io::Result<()> {
+  write_lines(ctx, rule_with_comment, |str| {
+    writer.write_all(str.as_bytes())
+  })
+}
+
+/// フォーマットした結果を`std::fmt::Write`に書き出す
+/// 行は`Context::break_str`で区切り、最後の行の後ろには何も書かない
+/// 最上位の`List`・`Table`は要素ごとにフォーマットし、確定した行から書き出していく
+/// 最上位がそれ以外のルールであれば、すべての行をフォーマットし終えてから書き出す
+pub fn code_format_to_fmt<W: std::fmt::Write>(
+  ctx: &Context,
+  rule_with_comment: &RuleWithComment,
+  writer: &mut W,
+) -> std::fmt::Result {
+  write_lines(ctx, rule_with_comment, |str| writer.write_str(str))
+}
+
+/// 行を一つの文字列に結合せずに、確定した行から書き出して解放していく
+/// 書き出しに失敗したら、それ以降の行は書き出さない
+fn write_lines<E>(
+  ctx: &Context,
+  rule_with_comment: &RuleWithComment,
+  mut write: impl FnMut(&str) -> Result<(), E>,
+) -> Result<(), E> {
+  let mut result = Ok(());
+  let mut is_first = true;
+  format::code_format(ctx, rule_with_comment, |line| {
+    if result.is_err() {
+      return;
+    }
+    if !is_first {
+      result = write(&ctx.break_str);
+    }
+    if result.is_ok() {
+      result = write(&line.code);
+    }
+    is_first = false
+  });
+  result
 }
 
 pub trait Ast2RuleWithComment {
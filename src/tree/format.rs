@@ -1,405 +1,2044 @@
+use std::{
+  cell::RefCell,
+  collections::{HashMap, VecDeque},
+};
+
 use crate::{
-  tree::{Rule, RuleWithComment},
-  ColumnConfig, Context,
+  tree::{Comment, CommentKind, CommentPlacement, Rule, RuleWithComment},
+  ColumnConfig, ColumnLayout, Context, ListConfig, ListLayout, SeparatorPosition,
+  TrailingSeparator,
 };
 
-#[allow(unreachable_patterns)]
-pub fn code_format(ctx: &Context, rule_with_comment: &RuleWithComment) -> (Vec<String>, bool) {
-  let rule = &rule_with_comment.rule;
-  match rule {
-    Rule::AST(ast) => {
-      let mut v = vec![];
-      if let Some(mut code_vec) = before_comments_format(ctx, &rule_with_comment.before_comments) {
-        v.append(&mut code_vec)
-      }
-      let mut rule_format_vec = code_format(ctx, ast).0;
-      v.append(&mut rule_format_vec);
-      if let Some(after_comment) = &rule_with_comment.after_comment {
-        v.push(after_comment.to_string());
-        (v, true)
-      } else {
-        (v, false)
+/// フォーマット結果の一行
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Line {
+  pub code: String,
+  /// インデントを付けずにそのまま出力する行かどうか
+  pub is_verbatim: bool,
+}
+
+impl Line {
+  pub fn new(code: String) -> Self {
+    Line {
+      code,
+      is_verbatim: false,
+    }
+  }
+  /// 行頭にインデントを付ける
+  fn indent(self, tab: &str) -> Self {
+    if self.is_verbatim || self.code.is_empty() || tab.is_empty() {
+      self
+    } else {
+      Line::new(format!("{tab}{}", self.code))
+    }
+  }
+}
+
+/// 子の行を後ろに追加する
+/// 深く入れ子になったルールで同じ行を何度も移し替えないように、少ない方の行を多い方へ移す
+fn append_lines(v: &mut VecDeque<Line>, mut lines: VecDeque<Line>) {
+  if v.len() < lines.len() {
+    while let Some(line) = v.pop_back() {
+      lines.push_front(line)
+    }
+    *v = lines
+  } else {
+    v.append(&mut lines)
+  }
+}
+
+/// 一行であることが保証されている行を一つの文字列にする
+fn join_code(lines: &VecDeque<Line>) -> String {
+  lines.iter().map(|line| line.code.as_str()).collect()
+}
+
+/// ルールごとに一度だけ計算する情報
+#[derive(Clone, Copy, Debug, Default)]
+struct Measure {
+  /// 前のコメントを除いた出力が、子孫に必ず改行するものがあるために必ず複数行になるかどうか
+  is_break_forced: bool,
+  /// 出力の最後の行が直後のコメントで終わるかどうか
+  is_ends_with_comment: bool,
+  /// 一行に並べたときの幅（直後のコメントを含む）
+  flat_width: usize,
+  /// 一行に並べるために必要な一行の長さの制限の最小値
+  flat_limit: usize,
+}
+
+/// 木全体を一度走査して得た情報を使ってフォーマットする
+/// 一行に収まるかどうかを子をフォーマットし直さずに判断できるので、木の大きさに比例する時間でフォーマットできる
+struct Formatter {
+  measures: HashMap<*const RuleWithComment, Measure>,
+  /// `format_children`で揃えた`Table`の行
+  /// 揃える計算を二度しないように、`break_token_table`で取り出して使う
+  table_codes: RefCell<HashMap<*const RuleWithComment, Vec<Option<String>>>>,
+  /// フォーマットした前のコメントの行数
+  /// `List`が要素の先頭の行を探すときに、コメントをフォーマットし直さずに使う
+  before_comment_lens: RefCell<HashMap<*const RuleWithComment, usize>>,
+}
+
+/// フォーマットする作業の単位
+type FormatTask<'c, 'r> = (Context<'c>, &'r RuleWithComment, GroupMode);
+
+/// 囲んでいるルールによって決まる`List`・`Column`のフォーマットのしかた
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum GroupMode {
+  /// 一行に収まれば一行に並べる
+  Auto,
+  /// `List`を、一行に収まっても複数行に分割する
+  Broken,
+  /// `List`を複数行に分割し、インデントを付けずに開き括弧の直後の位置に揃える
+  Hanging,
+  /// `Column`が囲んでいる`Column`の継続行に置かれている
+  /// 継続行のインデントは囲んでいる`Column`が付けるので、分割しても継続行のインデントを付けない
+  Continued,
+}
+
+/// フォーマットした行を先頭から順に`emit`に渡す
+pub fn code_format(ctx: &Context, rule_with_comment: &RuleWithComment, emit: impl FnMut(Line)) {
+  Formatter::new(ctx, rule_with_comment).code_format(ctx, rule_with_comment, emit)
+}
+
+/// 再帰呼び出しの代わりに明示的なスタックを使って、木を帰りがけ順に処理する
+/// `children`で子の作業を順に列挙し、`combine`で子の結果から親の結果を作る
+/// 木がどれだけ深くてもコールスタックを消費しない
+fn traverse<T, R>(
+  root: T,
+  mut children: impl FnMut(&T) -> Vec<T>,
+  mut combine: impl FnMut(T, Vec<R>) -> R,
+) -> R {
+  enum Task<T> {
+    Expand(T),
+    Combine(T, usize),
+  }
+  let mut tasks = vec![Task::Expand(root)];
+  let mut results = vec![];
+  while let Some(task) = tasks.pop() {
+    match task {
+      Task::Expand(item) => {
+        let child_items = children(&item);
+        tasks.push(Task::Combine(item, child_items.len()));
+        // 先頭の子から処理されるように逆順に積む
+        tasks.extend(child_items.into_iter().rev().map(Task::Expand));
       }
+      Task::Combine(item, len) => {
+        let child_results = results.split_off(results.len() - len);
+        results.push(combine(item, child_results));
+      }
+    }
+  }
+  results.pop().unwrap()
+}
+
+/// 子のルールを出力される順に並べる
+fn child_rules(rule_with_comment: &RuleWithComment) -> Vec<&RuleWithComment> {
+  match &rule_with_comment.rule {
+    Rule::AST(child) | Rule::Paren(_, child, _, _) | Rule::Dedent(child) => vec![child],
+    Rule::List(_, lst, _) | Rule::Table(lst) => lst.iter().collect(),
+    Rule::Column(lst) => lst.iter().map(|(child, _)| child).collect(),
+    Rule::IfBreak(break_rule_with_comment, flat_rule_with_comment) => {
+      vec![break_rule_with_comment, flat_rule_with_comment]
     }
-    Rule::Raw(str) => {
-      let mut v = vec![];
-      if let Some(mut code_vec) = before_comments_format(ctx, &rule_with_comment.before_comments) {
-        v.append(&mut code_vec)
+    Rule::Raw(_) | Rule::Verbatim(_) | Rule::HardLine | Rule::BlankLine(_) => vec![],
+  }
+}
+
+impl Formatter {
+  fn new(ctx: &Context, rule_with_comment: &RuleWithComment) -> Self {
+    let mut formatter = Formatter {
+      measures: HashMap::new(),
+      table_codes: RefCell::new(HashMap::new()),
+      before_comment_lens: RefCell::new(HashMap::new()),
+    };
+    traverse(
+      rule_with_comment,
+      |rule_with_comment| child_rules(rule_with_comment),
+      |rule_with_comment, child_measures| formatter.measure(ctx, rule_with_comment, child_measures),
+    );
+    formatter
+  }
+
+  /// 子から親へ向かって、必ず改行するかどうかと一行に並べたときの幅を伝播させる
+  /// `child_measures`は`child_rules`の順に並んだ子の情報
+  fn measure(
+    &mut self,
+    ctx: &Context,
+    rule_with_comment: &RuleWithComment,
+    child_measures: Vec<Measure>,
+  ) -> Measure {
+    let mut is_break_forced = false;
+    let mut is_ends_with_comment = false;
+    let mut flat_width = 0;
+    let mut flat_limit = 0;
+    match &rule_with_comment.rule {
+      Rule::AST(child_rule_with_comment) => {
+        let measure = child_measures[0];
+        // 直後のコメントは次の行に置かれる
+        is_break_forced = self.is_break_forced(child_rule_with_comment)
+          || rule_with_comment.after_comment.is_some();
+        is_ends_with_comment = measure.is_ends_with_comment;
+        flat_width = measure.flat_width;
+        flat_limit = measure.flat_limit;
       }
-      if let Some(after_comment) = &rule_with_comment.after_comment {
-        let comment = (ctx.oneline_comment_format)(after_comment.to_string());
-        if let Some(joiner) = &ctx.list_join_str {
-          v.push(format!("{str}{joiner} {comment}"))
+      Rule::Raw(str) => flat_width = ctx.width(str),
+      Rule::Verbatim(str) => {
+        is_break_forced = str.contains('\n');
+        flat_width = ctx.width(str)
+      }
+      Rule::Paren(open, child_rule_with_comment, close, config) => {
+        let measure = child_measures[0];
+        is_break_forced = self.is_break_forced(child_rule_with_comment)
+          || measure.is_ends_with_comment
+          || config.open_comment.is_some()
+          || config.close_comment.is_some();
+        flat_width = ctx.width(open) + measure.flat_width + ctx.width(close);
+        // 一行に収まるかどうかは中身だけで判断する
+        flat_limit = measure.flat_limit;
+      }
+      Rule::List(join, lst, config) => {
+        let trailing_join_width = config.space_before_separator.unwrap_or(0) + ctx.width(join);
+        for (i, (child_rule_with_comment, measure)) in lst.iter().zip(child_measures).enumerate() {
+          let is_last = i == lst.len() - 1;
+          let inline_comments = inline_comments_format(ctx, config, child_rule_with_comment);
+          let is_child_break_forced = match &inline_comments {
+            // 同じ行に置くコメントでは改行しない
+            Some(comments) => {
+              flat_width += ctx.width(comments) + 1;
+              measure.is_break_forced
+            }
+            None => self.is_break_forced(child_rule_with_comment),
+          };
+          is_break_forced |= is_child_break_forced || (!is_last && measure.is_ends_with_comment);
+          is_ends_with_comment = is_last && measure.is_ends_with_comment;
+          flat_width += measure.flat_width;
+          if !is_last {
+            flat_width += trailing_join_width + config.space_after_separator.unwrap_or(1);
+          }
+        }
+        if !lst.is_empty()
+          && config.separator_position == SeparatorPosition::Trailing
+          && config.trailing_separator == TrailingSeparator::Always
+        {
+          flat_width += trailing_join_width;
+        }
+        // 一行の長さの制限よりも短い場合に一行になる
+        flat_limit = flat_width + 1;
+      }
+      Rule::Column(lst) => {
+        for (i, ((child_rule_with_comment, config), measure)) in
+          lst.iter().zip(child_measures).enumerate()
+        {
+          let is_last = i == lst.len() - 1;
+          is_break_forced |= self.is_break_forced(child_rule_with_comment)
+            || (!is_last && (measure.is_ends_with_comment || config.is_break == Some(true)));
+          is_ends_with_comment = is_last && measure.is_ends_with_comment;
+          flat_width += measure.flat_width;
+          if !is_last {
+            flat_width += config.space_size.unwrap_or(1);
+          }
+        }
+        flat_limit = flat_width;
+      }
+      Rule::Table(rows) => {
+        // 直後のコメントは次の行に置かれる
+        is_break_forced = rows.len() > 1 || rule_with_comment.after_comment.is_some();
+        for (row, measure) in rows.iter().zip(child_measures) {
+          is_break_forced |= self.is_break_forced(row);
+          is_ends_with_comment = measure.is_ends_with_comment;
+          flat_width = measure.flat_width;
+          flat_limit = measure.flat_limit;
+        }
+      }
+      Rule::IfBreak(_, flat_rule_with_comment) => {
+        // 必ず改行するかどうかは、一行に収まったときの出力で決まる
+        let measure = child_measures[1];
+        is_break_forced = self.is_break_forced(flat_rule_with_comment)
+          || (measure.is_ends_with_comment && rule_with_comment.after_comment.is_some());
+        is_ends_with_comment = measure.is_ends_with_comment;
+        flat_width = measure.flat_width;
+        flat_limit = measure.flat_limit;
+      }
+      Rule::HardLine | Rule::BlankLine(_) => is_break_forced = true,
+      Rule::Dedent(_) => {
+        is_break_forced = true;
+        is_ends_with_comment = child_measures[0].is_ends_with_comment
+      }
+    }
+    if let Some(after_comment) = &rule_with_comment.after_comment {
+      let comment = after_comment_format(ctx, after_comment);
+      flat_width += 1 + ctx.width(&comment);
+      is_break_forced |= after_comment.placement == CommentPlacement::OwnLine;
+    }
+    let measure = Measure {
+      is_break_forced,
+      is_ends_with_comment: is_ends_with_comment || rule_with_comment.after_comment.is_some(),
+      flat_width,
+      flat_limit,
+    };
+    self
+      .measures
+      .insert(rule_with_comment as *const RuleWithComment, measure);
+    measure
+  }
+
+  /// `Formatter::new`で渡した木の中のルールの情報を得る
+  fn get_measure(&self, rule_with_comment: &RuleWithComment) -> Measure {
+    self
+      .measures
+      .get(&(rule_with_comment as *const RuleWithComment))
+      .copied()
+      .unwrap_or_default()
+  }
+
+  /// 前のコメントも含めて、出力が必ず複数行になるかどうか
+  /// 囲んでいる`List`・`Column`・`Paren`はこれが成り立つ子を含むと必ず複数行に分割される
+  fn is_break_forced(&self, rule_with_comment: &RuleWithComment) -> bool {
+    !rule_with_comment.before_comments.is_empty()
+      || self.get_measure(rule_with_comment).is_break_forced
+  }
+
+  /// 前のコメントを除いて一行に収まるかどうか
+  fn is_flat(&self, ctx: &Context, rule_with_comment: &RuleWithComment) -> bool {
+    let measure = self.get_measure(rule_with_comment);
+    !measure.is_break_forced && measure.flat_limit <= ctx.len_max()
+  }
+
+  /// 一行に収まるルールを、前のコメントを除いて一行に並べる
+  fn flat_format(&self, ctx: &Context, rule_with_comment: &RuleWithComment) -> (String, bool) {
+    traverse(
+      (ctx.clone(), rule_with_comment),
+      |(ctx, rule_with_comment)| flat_children(ctx, rule_with_comment),
+      |(ctx, rule_with_comment), formatted| flat_combine(&ctx, rule_with_comment, formatted),
+    )
+  }
+
+  /// 最上位の`List`・`Table`は要素を一つずつフォーマットし、後の要素によって書き換えられることのない行から`emit`に渡していく
+  pub(crate) fn code_format(
+    &self,
+    ctx: &Context,
+    rule_with_comment: &RuleWithComment,
+    mut emit: impl FnMut(Line),
+  ) {
+    let formatted = self
+      .format_children(ctx, rule_with_comment, GroupMode::Auto)
+      .into_iter()
+      .map(|task| self.format_task(task));
+    let (v, _) = self.format_combine(
+      ctx,
+      rule_with_comment,
+      GroupMode::Auto,
+      formatted,
+      Some(&mut emit),
+    );
+    v.into_iter().for_each(emit)
+  }
+
+  fn format_task(&self, task: FormatTask) -> (VecDeque<Line>, bool) {
+    traverse(
+      task,
+      |(ctx, rule_with_comment, group_mode)| {
+        self.format_children(ctx, rule_with_comment, *group_mode)
+      },
+      |(ctx, rule_with_comment, group_mode), formatted| {
+        self.format_combine(&ctx, rule_with_comment, group_mode, formatted, None)
+      },
+    )
+  }
+
+  /// 子をフォーマットせずに、一行に並べて出力するかどうか
+  fn is_flat_group(
+    &self,
+    ctx: &Context,
+    rule_with_comment: &RuleWithComment,
+    group_mode: GroupMode,
+  ) -> bool {
+    !matches!(group_mode, GroupMode::Broken | GroupMode::Hanging)
+      && matches!(
+        rule_with_comment.rule,
+        Rule::List(..) | Rule::Paren(..) | Rule::Column(..)
+      )
+      && self.is_flat(ctx, rule_with_comment)
+  }
+
+  /// フォーマットする前に結果が必要な子の作業を、出力される順に並べる
+  fn format_children<'c, 'r>(
+    &self,
+    ctx: &Context<'c>,
+    rule_with_comment: &'r RuleWithComment,
+    group_mode: GroupMode,
+  ) -> Vec<FormatTask<'c, 'r>> {
+    if self.is_flat_group(ctx, rule_with_comment, group_mode) {
+      return vec![];
+    }
+    match &rule_with_comment.rule {
+      Rule::AST(ast) => vec![(ctx.clone(), ast, group_mode)],
+      Rule::Paren(open, child_rule_with_comment, _, config) => {
+        if config.is_aligned {
+          let child_ctx = ctx
+            .add_offset(ctx.width(open))
+            .set_list_join_str(None)
+            .set_is_broken(true);
+          let group_mode = if matches!(child_rule_with_comment.rule, Rule::List(..)) {
+            GroupMode::Hanging
+          } else {
+            GroupMode::Auto
+          };
+          vec![(child_ctx, child_rule_with_comment, group_mode)]
         } else {
-          v.push(format!("{str} {comment}"))
+          let child_ctx = ctx
+            .increment_depth()
+            .set_list_join_str(None)
+            .set_is_broken(true);
+          // 括弧にコメントが付いて複数行になるときは、中身のリストも一行ずつ並べる
+          let is_commented = config.open_comment.is_some() || config.close_comment.is_some();
+          let group_mode = if is_commented && matches!(child_rule_with_comment.rule, Rule::List(..))
+          {
+            GroupMode::Broken
+          } else {
+            GroupMode::Auto
+          };
+          vec![(child_ctx, child_rule_with_comment, group_mode)]
         }
-        (v, true)
-      } else {
-        v.push(str.to_string());
-        (v, false)
       }
+      Rule::List(join, lst, config) => {
+        list_child_contexts(ctx, join, config, lst, group_mode == GroupMode::Hanging)
+          .into_iter()
+          .zip(lst)
+          .map(|(child_ctx, child_rule_with_comment)| {
+            (child_ctx, child_rule_with_comment, GroupMode::Auto)
+          })
+          .collect()
+      }
+      Rule::Column(lst) => {
+        let ctx = ctx.set_is_broken(true);
+        lst
+          .iter()
+          .enumerate()
+          .map(|(i, (child_rule_with_comment, _))| {
+            let (child_ctx, child_group_mode) = column_child_context(&ctx, group_mode, i);
+            (child_ctx, child_rule_with_comment, child_group_mode)
+          })
+          .collect()
+      }
+      Rule::IfBreak(break_rule_with_comment, flat_rule_with_comment) => {
+        if ctx.is_broken {
+          vec![(ctx.clone(), break_rule_with_comment, group_mode)]
+        } else {
+          vec![(ctx.clone(), flat_rule_with_comment, group_mode)]
+        }
+      }
+      Rule::Table(rows) => {
+        // 揃えられなかった行だけを通常通りフォーマットする
+        let child_ctx = ctx.set_list_join_str(None);
+        let aligned_codes = self.table_aligned_codes(ctx, rows);
+        let tasks = rows
+          .iter()
+          .zip(&aligned_codes)
+          .filter(|(_, code_opt)| code_opt.is_none())
+          .map(|(row, _)| (child_ctx.clone(), row, GroupMode::Auto))
+          .collect();
+        // 揃えた結果は`break_token_table`でそのまま使う
+        self
+          .table_codes
+          .borrow_mut()
+          .insert(rule_with_comment, aligned_codes);
+        tasks
+      }
+      Rule::Dedent(child_rule_with_comment) => vec![(
+        ctx.reset_depth().set_list_join_str(None),
+        child_rule_with_comment,
+        GroupMode::Auto,
+      )],
+      Rule::Raw(_) | Rule::Verbatim(_) | Rule::HardLine | Rule::BlankLine(_) => vec![],
+    }
+  }
+
+  /// `format_children`で並べた子のフォーマット結果を使ってフォーマットする
+  /// `emit`を渡した場合は、確定した行を`emit`に渡して結果から取り除いていく
+  fn format_combine(
+    &self,
+    ctx: &Context,
+    rule_with_comment: &RuleWithComment,
+    group_mode: GroupMode,
+    formatted: impl IntoIterator<Item = (VecDeque<Line>, bool)>,
+    emit: Option<&mut dyn FnMut(Line)>,
+  ) -> (VecDeque<Line>, bool) {
+    let Some(own_line_comment) = rule_with_comment
+      .after_comment
+      .as_ref()
+      .filter(|comment| comment.placement == CommentPlacement::OwnLine)
+    else {
+      return self.format_rule(
+        ctx,
+        rule_with_comment,
+        &rule_with_comment.after_comment,
+        group_mode,
+        formatted,
+        emit,
+      );
+    };
+    // 独立した行に置くコメントは、要素を直後のコメント無しでフォーマットしてから次の行に置く
+    let (mut v, is_exists_after_comment) =
+      self.format_rule(ctx, rule_with_comment, &None, group_mode, formatted, emit);
+    if let (Some(last), Some(joiner)) = (v.back_mut(), &ctx.list_join_str) {
+      if !is_exists_after_comment {
+        last.code.push_str(joiner)
+      }
+    }
+    // 独立した行に置くので、前のコメントと同じように複数行にフォーマットする
+    v.extend(
+      before_comments_format(ctx, std::slice::from_ref(own_line_comment)).unwrap_or_default(),
+    );
+    (v, true)
+  }
+
+  /// 要素の前のコメントをフォーマットし、行数を記録しておく
+  fn before_comments_lines(
+    &self,
+    ctx: &Context,
+    rule_with_comment: &RuleWithComment,
+  ) -> VecDeque<Line> {
+    let v = before_comments_format(ctx, &rule_with_comment.before_comments).unwrap_or_default();
+    self
+      .before_comment_lens
+      .borrow_mut()
+      .insert(rule_with_comment, v.len());
+    v
+  }
+
+  /// 直後のコメントとして`after_comment_opt`を使ってフォーマットする
+  fn format_rule(
+    &self,
+    ctx: &Context,
+    rule_with_comment: &RuleWithComment,
+    after_comment_opt: &Option<Comment>,
+    group_mode: GroupMode,
+    formatted: impl IntoIterator<Item = (VecDeque<Line>, bool)>,
+    emit: Option<&mut dyn FnMut(Line)>,
+  ) -> (VecDeque<Line>, bool) {
+    if self.is_flat_group(ctx, rule_with_comment, group_mode) {
+      // 一行に収まる場合は、子をフォーマットし直さずにそのまま一行に並べる
+      let mut v = self.before_comments_lines(ctx, rule_with_comment);
+      let (code, is_exists_after_comment) =
+        self.flat_format(&ctx.set_is_broken(false), rule_with_comment);
+      v.push_back(Line::new(code));
+      return (v, is_exists_after_comment);
     }
-    Rule::Paren(open, child_rule_with_comment, close) => {
-      let (str_lst, is_exist_after_comment) =
-        code_format(&ctx.set_list_join_str(None), child_rule_with_comment);
-      let mut v = Vec::new();
-      if let Some(mut code_vec) = before_comments_format(ctx, &rule_with_comment.before_comments) {
-        v.append(&mut code_vec)
+    let mut formatted = formatted.into_iter();
+    match &rule_with_comment.rule {
+      Rule::AST(_) => {
+        let mut v = self.before_comments_lines(ctx, rule_with_comment);
+        let (rule_format_vec, is_exists_after_comment) = formatted.next().unwrap();
+        append_lines(&mut v, rule_format_vec);
+        if let Some(after_comment) = after_comment_opt {
+          v.push_back(Line::new(after_comment_format(ctx, after_comment)));
+          (v, true)
+        } else {
+          (v, is_exists_after_comment)
+        }
       }
-      if str_lst.len() <= 1 && !is_exist_after_comment {
-        if let Some(after_comment) = &rule_with_comment.after_comment {
+      Rule::Raw(str) => {
+        let mut v = self.before_comments_lines(ctx, rule_with_comment);
+        if let Some(after_comment) = after_comment_opt {
+          let comment = after_comment_format(ctx, after_comment);
           if let Some(joiner) = &ctx.list_join_str {
-            v.push(format!(
-              "{open}{}{close}{joiner} {}",
-              str_lst.join(""),
-              (ctx.oneline_comment_format)(after_comment.to_string())
-            ));
+            v.push_back(Line::new(format!("{str}{joiner} {comment}")))
           } else {
-            v.push(format!(
-              "{open}{}{close} {}",
-              str_lst.join(""),
-              (ctx.oneline_comment_format)(after_comment.to_string())
-            ));
+            v.push_back(Line::new(format!("{str} {comment}")))
           }
           (v, true)
         } else {
-          v.push(format!("{open}{}{close}", str_lst.join("")));
+          v.push_back(Line::new(str.to_string()));
           (v, false)
         }
-      } else {
-        v.push(open.to_string());
-        for str in code_format(
-          &ctx.increment_depth().set_list_join_str(None),
-          child_rule_with_comment,
-        )
-        .0
-        {
-          v.push(str.to_string())
+      }
+      Rule::Verbatim(str) => {
+        let mut v = self.before_comments_lines(ctx, rule_with_comment);
+        // 先頭の行以外はインデントを付けずにそのまま出力する
+        for (i, code) in str.split('\n').enumerate() {
+          let code = code.strip_suffix('\r').unwrap_or(code).to_string();
+          v.push_back(Line {
+            code,
+            is_verbatim: i > 0,
+          })
         }
-        if let Some(after_comment) = &rule_with_comment.after_comment {
-          let comment = (ctx.oneline_comment_format)(after_comment.to_string());
+        if let Some(after_comment) = after_comment_opt {
+          let comment = after_comment_format(ctx, after_comment);
+          let last = v.back_mut().unwrap();
           if let Some(joiner) = &ctx.list_join_str {
-            v.push(format!("{close}{joiner} {comment}"))
+            last.code = format!("{}{joiner} {comment}", last.code)
           } else {
-            v.push(format!("{close} {comment}"))
+            last.code = format!("{} {comment}", last.code)
           }
           (v, true)
         } else {
-          v.push(close.to_string());
           (v, false)
         }
       }
+      Rule::Paren(open, child_rule_with_comment, close, config) => {
+        // 一行に収まらなかった
+        let mut v = self.before_comments_lines(ctx, rule_with_comment);
+        let (mut code_vec, mut is_exists_after_comment) = formatted.next().unwrap();
+        // 閉じ括弧の直前のコメントは、中身と同じインデントで中身の後ろの行に置く
+        let is_indented =
+          !config.is_aligned && matches!(child_rule_with_comment.rule, Rule::List(..));
+        let comment_ctx = if config.is_aligned {
+          ctx.add_offset(ctx.width(open))
+        } else if is_indented {
+          ctx.increment_depth()
+        } else {
+          ctx.clone()
+        };
+        if let Some(comment_vec) =
+          before_comments_format(&comment_ctx, config.close_comment.as_slice())
+        {
+          let tab = if is_indented {
+            ctx.indent()
+          } else {
+            String::new()
+          };
+          code_vec.extend(comment_vec.into_iter().map(|line| line.indent(&tab)));
+          is_exists_after_comment = true;
+        }
+        // 開き括弧の直後のコメントは開き括弧と同じ行に置く
+        let open_line = config
+          .open_comment
+          .as_ref()
+          .map(|comment| Line::new(format!("{open} {}", after_comment_format(ctx, comment))));
+        if config.is_aligned {
+          // 中身を開き括弧の直後から始め、続く行を開き括弧の直後の位置に揃える
+          // 開き括弧の直後にコメントがある場合は、中身を次の行から始める
+          let pad = " ".repeat(ctx.width(open));
+          let is_open_line_exists = open_line.is_some();
+          for (i, line) in code_vec.iter_mut().enumerate() {
+            *line = if i == 0 && !is_open_line_exists {
+              Line::new(format!("{open}{}", line.code))
+            } else {
+              std::mem::take(line).indent(&pad)
+            }
+          }
+          v.extend(open_line);
+          append_lines(&mut v, code_vec);
+          match v.back_mut() {
+            // 直後のコメントの後ろには閉じ括弧を置けない
+            Some(last) if !is_exists_after_comment => last.code.push_str(close),
+            _ => v.push_back(Line::new(close.to_string())),
+          }
+          if let Some(after_comment) = after_comment_opt {
+            let comment = after_comment_format(ctx, after_comment);
+            let last = v.back_mut().unwrap();
+            if let Some(joiner) = &ctx.list_join_str {
+              last.code = format!("{}{joiner} {comment}", last.code)
+            } else {
+              last.code = format!("{} {comment}", last.code)
+            }
+            (v, true)
+          } else {
+            (v, false)
+          }
+        } else {
+          v.push_back(open_line.unwrap_or_else(|| Line::new(open.to_string())));
+          append_lines(&mut v, code_vec);
+          if let Some(after_comment) = after_comment_opt {
+            let comment = after_comment_format(ctx, after_comment);
+            if let Some(joiner) = &ctx.list_join_str {
+              v.push_back(Line::new(format!("{close}{joiner} {comment}")))
+            } else {
+              v.push_back(Line::new(format!("{close} {comment}")))
+            }
+            (v, true)
+          } else {
+            v.push_back(Line::new(close.to_string()));
+            (v, false)
+          }
+        }
+      }
+      Rule::List(join, lst, config) => self.break_token_list(
+        ctx,
+        join,
+        config,
+        self.before_comments_lines(ctx, rule_with_comment),
+        lst,
+        after_comment_opt,
+        group_mode == GroupMode::Hanging,
+        formatted,
+        emit,
+      ),
+      Rule::Column(lst) => {
+        // 一行に収まらなかった
+        let ctx = ctx.set_is_broken(true);
+        self.break_token_column_with_layout(
+          &ctx,
+          self.before_comments_lines(&ctx, rule_with_comment),
+          lst,
+          after_comment_opt,
+          group_mode,
+          formatted.collect(),
+        )
+      }
+      Rule::IfBreak(..) => {
+        let mut v = self.before_comments_lines(ctx, rule_with_comment);
+        let (code_vec, is_exists_after_comment) = formatted.next().unwrap();
+        append_lines(&mut v, code_vec);
+        if let Some(after_comment) = after_comment_opt {
+          let comment = after_comment_format(ctx, after_comment);
+          match v.back_mut() {
+            Some(last) if !is_exists_after_comment => {
+              if let Some(joiner) = &ctx.list_join_str {
+                last.code = format!("{}{joiner} {comment}", last.code)
+              } else {
+                last.code = format!("{} {comment}", last.code)
+              }
+            }
+            _ => v.push_back(Line::new(comment)),
+          }
+          (v, true)
+        } else {
+          (v, is_exists_after_comment)
+        }
+      }
+      Rule::Table(rows) => self.break_token_table(
+        ctx,
+        self.before_comments_lines(ctx, rule_with_comment),
+        rule_with_comment,
+        rows,
+        after_comment_opt,
+        formatted,
+        emit,
+      ),
+      Rule::HardLine => line_break_format(ctx, rule_with_comment, 0),
+      Rule::BlankLine(n) => line_break_format(ctx, rule_with_comment, *n),
+      Rule::Dedent(_) => {
+        let mut v = self.before_comments_lines(ctx, rule_with_comment);
+        let (mut code_vec, is_exists_after_comment) = formatted.next().unwrap();
+        for line in code_vec.iter_mut() {
+          line.is_verbatim = true
+        }
+        append_lines(&mut v, code_vec);
+        if let Some(after_comment) = after_comment_opt {
+          let comment = after_comment_format(ctx, after_comment);
+          match v.back_mut() {
+            Some(last) if !is_exists_after_comment => {
+              last.code = format!("{} {comment}", last.code)
+            }
+            _ => v.push_back(Line::new(comment)),
+          }
+          (v, true)
+        } else {
+          (v, is_exists_after_comment)
+        }
+      }
     }
-    Rule::List(join, lst) => break_token_list(
-      ctx,
-      join,
-      &rule_with_comment.before_comments,
-      lst,
-      &rule_with_comment.after_comment,
-    ),
-    Rule::Column(lst) => break_token_column(
-      ctx,
-      &rule_with_comment.before_comments,
-      lst,
-      &rule_with_comment.after_comment,
-    ),
-    _ => (vec![], false),
   }
-}
 
-/// Listルールをフォーマットする
-fn break_token_list(
-  ctx: &Context,
-  join: &str,
-  before_comments: &[String],
-  lst: &[RuleWithComment],
-  after_comment_opt: &Option<String>,
-) -> (Vec<String>, bool) {
-  let tab = ctx.indent();
-  let mut is_multiline = false;
-  for (i, new_rule_with_comment) in lst.iter().enumerate() {
-    let (code_str_lst, is_last_exists_after_comment) = code_format(
-      &ctx.set_list_join_str(Some(join.to_string())),
-      new_rule_with_comment,
-    );
-    if
-    // 要素の前のコメントが存在する要素が一つでもあるか、
-    !new_rule_with_comment.before_comments.is_empty()
-    // 最後の要素以外の要素で、要素直後のコメントが一つでも存在するか、
-    || (i < lst.len() - 1 && is_last_exists_after_comment)
-    // 出力結果が複数行のとき
-    || code_str_lst.len() > 1
-    {
-      is_multiline = true;
-      break;
-    }
-  }
-  if !is_multiline {
-    let mut is_oneline_last_comment_exsits = false;
-    let str = lst
-      .iter()
-      .enumerate()
-      .map(|(i, child_rule_with_comment)| {
-        let (code_str, is_last_exsits_after_comment) = code_format(
-          &ctx.set_list_join_str(Some(join.to_string())),
-          child_rule_with_comment,
-        );
-        if i == lst.len() - 1 && is_last_exsits_after_comment {
-          // 最後の要素の直後にコメントがあった場合にフラグをたてる
-          is_oneline_last_comment_exsits = true
-        }
-        // 一行であることが保障されている
-        code_str.join("")
-      })
-      .collect::<Vec<_>>()
-      .join(&format!("{join} "));
-    if str.len() < ctx.len_max() {
-      // 内部が一行で表せて、かつその長さが設定されている一行の長さよりも短い場合にonelineとなる
-      let mut v = Vec::new();
-      if let Some(mut code_vec) = before_comments_format(ctx, before_comments) {
-        v.append(&mut code_vec)
-      }
-      if let Some(after_comment) = after_comment_opt {
-        let comment = (ctx.oneline_comment_format)(after_comment.to_string());
-        v.push(format!("{str} {comment}"));
-        return (v, true);
-      } else {
-        v.push(str);
-        return (v, is_oneline_last_comment_exsits);
+  /// `Context::column_layout`で指定されたアルゴリズムでColumnルールをフォーマットする
+  /// `v`はフォーマット済みの前のコメントの行
+  fn break_token_column_with_layout(
+    &self,
+    ctx: &Context,
+    v: VecDeque<Line>,
+    lst: &[(RuleWithComment, ColumnConfig)],
+    after_comment_opt: &Option<Comment>,
+    group_mode: GroupMode,
+    formatted: Vec<(VecDeque<Line>, bool)>,
+  ) -> (VecDeque<Line>, bool) {
+    // 最初の要素の行に続く行が継続行になる
+    let indent_start = v.len() + formatted.first().map_or(1, |(lines, _)| lines.len().max(1));
+    // 継続行に置かれている場合は、継続行のインデントの分の幅を既に空けている
+    let ctx = if group_mode == GroupMode::Continued {
+      ctx.set_continuation_indent(0)
+    } else {
+      ctx.clone()
+    };
+    let (mut v, is_last_exists_after_comment) = match ctx.column_layout {
+      ColumnLayout::Greedy => {
+        self.break_token_column(&ctx, v, lst, after_comment_opt, indent_start, formatted)
+      }
+      ColumnLayout::Optimal => self.break_token_column_by_segment(
+        &ctx,
+        v,
+        lst,
+        after_comment_opt,
+        indent_start,
+        formatted,
+        layout_column_segment,
+      ),
+      ColumnLayout::Consistent => self.break_token_column_by_segment(
+        &ctx,
+        v,
+        lst,
+        after_comment_opt,
+        indent_start,
+        formatted,
+        |_, segment, _| split_column_segment(segment),
+      ),
+    };
+    // 継続行にインデントを付ける
+    // 最初の要素の行は、最初の要素が`Column`であればその`Column`がインデントを付けている
+    if ctx.continuation_indent > 0 {
+      let tab = " ".repeat(ctx.continuation_indent);
+      for line in v.iter_mut().skip(indent_start) {
+        *line = std::mem::take(line).indent(&tab)
       }
     }
+    (v, is_last_exists_after_comment)
   }
-  let mut v = Vec::new();
-  if let Some(mut code_vec) = before_comments_format(ctx, before_comments) {
-    v.append(&mut code_vec)
-  }
-  let mut lst = lst
-    .iter()
-    .map(|new_rule_with_comment| {
-      code_format(
-        &ctx
-          .increment_depth()
-          .set_list_join_str(Some(join.to_string())),
-        new_rule_with_comment,
-      )
-    })
-    .peekable();
-  while let Some((code_lst, is_exsits_after_comment)) = lst.next() {
-    let is_last = lst.peek().is_none(); // 全体の最後
-    let mut code_iter = code_lst.iter().peekable();
-    while let Some(code) = code_iter.next() {
-      let is_local_last = code_iter.peek().is_none();
-      if !is_last && is_local_last {
-        if is_exsits_after_comment {
-          // 前処理段階でjoin文字列が挿入されている
-          v.push(format!("{tab}{code}"))
+
+  /// Listルールをフォーマットする
+  /// `is_hanging`がtrueのときは、囲んでいる`Paren`が揃える位置を決めるので、複数行になってもインデントを付けない
+  /// `v`はフォーマット済みの前のコメントの行
+  #[allow(clippy::too_many_arguments)]
+  fn break_token_list(
+    &self,
+    ctx: &Context,
+    join: &str,
+    config: &ListConfig,
+    mut v: VecDeque<Line>,
+    lst: &[RuleWithComment],
+    after_comment_opt: &Option<Comment>,
+    is_hanging: bool,
+    formatted: impl IntoIterator<Item = (VecDeque<Line>, bool)>,
+    mut emit: Option<&mut dyn FnMut(Line)>,
+  ) -> (VecDeque<Line>, bool) {
+    let tab = if is_hanging {
+      String::new()
+    } else {
+      ctx.indent()
+    };
+    let is_leading = config.separator_position == SeparatorPosition::Leading;
+    let space_before = " ".repeat(config.space_before_separator.unwrap_or(0));
+    let space_after = " ".repeat(config.space_after_separator.unwrap_or(1));
+    // 行末に置く区切り文字
+    let trailing_join = format!("{space_before}{join}");
+    // 強制改行・空行を除いた最初の要素の位置
+    let first_index = lst.iter().position(|r| !is_line_break(r));
+    let child_ctx_lst = list_child_contexts(ctx, join, config, lst, is_hanging);
+    // 要素の直後のコメントで終わる行の位置と、その行のコメントの前の部分の長さ
+    let mut comment_lines = vec![];
+    // 要素を詰め込んでいる途中の行
+    let mut line = String::new();
+    // 最後の要素が直後のコメントで終わるかどうか
+    let mut is_last_exists_after_comment = false;
+    for (i, ((new_rule_with_comment, child_ctx), (mut code_lst, is_exsits_after_comment))) in
+      lst.iter().zip(child_ctx_lst).zip(formatted).enumerate()
+    {
+      if let Some(emit) = emit.as_deref_mut() {
+        emit_list_lines(ctx, config, &mut v, &mut comment_lines, emit)
+      }
+      is_last_exists_after_comment = is_exsits_after_comment;
+      if is_line_break(new_rule_with_comment) {
+        // 強制改行・空行は要素として扱わず、区切り文字も入れない
+        if !line.is_empty() {
+          v.push_back(Line::new(format!("{tab}{line}")));
+          line = String::new()
+        }
+        for code in code_lst {
+          push_indented_line(ctx, &mut v, &tab, code)
+        }
+        continue;
+      }
+      // 要素の後ろに区切り文字を入れる場合は、子に区切り文字を渡している
+      let is_joined = child_ctx.list_join_str.is_some();
+      let mut comment_len = self.before_comment_lens.borrow()[&(new_rule_with_comment as *const _)];
+      if let Some(comments) = inline_comments_format(&child_ctx, config, new_rule_with_comment) {
+        // 独立した行になっている前のコメントを、要素の先頭の行に移す
+        code_lst.drain(..comment_len);
+        if let Some(line) = code_lst.front_mut() {
+          line.code = format!("{comments} {}", line.code)
+        }
+        comment_len = 0
+      }
+      let is_oneline = code_lst.len() == comment_len + 1 && !code_lst[comment_len].is_verbatim;
+      if is_leading && first_index.is_some_and(|first_index| i > first_index) {
+        // 区切り文字は要素の前のコメントの後ろ、要素の先頭の行に置く
+        if let Some(line) = code_lst.get_mut(comment_len) {
+          line.code = format!("{join}{space_after}{}", line.code)
+        }
+      } else if !is_leading && is_joined && !is_exsits_after_comment {
+        // 要素直後のコメントがある場合は、前処理段階でjoin文字列が挿入されている
+        if let Some(line) = code_lst.back_mut() {
+          line.code.push_str(&trailing_join)
+        }
+      }
+      if config.layout == ListLayout::Fill && is_oneline {
+        // 前のコメントが無い一行の要素だけを同じ行に詰め込む
+        let code = code_lst.pop_back().unwrap().code;
+        if comment_len == 0 && !line.is_empty() {
+          let glue = if is_leading {
+            &space_before
+          } else {
+            &space_after
+          };
+          let new_line = format!("{line}{glue}{code}");
+          if ctx.width(&new_line) < child_ctx.len_max() {
+            line = new_line
+          } else {
+            v.push_back(Line::new(format!("{tab}{line}")));
+            line = code
+          }
         } else {
-          v.push(format!("{tab}{code}{join}"))
+          if !line.is_empty() {
+            v.push_back(Line::new(format!("{tab}{line}")));
+          }
+          for comment in code_lst {
+            v.push_back(comment.indent(&tab))
+          }
+          line = code
+        }
+        if is_exsits_after_comment {
+          // 直後のコメントの後ろには何も置けない
+          v.push_back(Line::new(format!("{tab}{line}")));
+          line = String::new()
         }
       } else {
-        v.push(format!("{tab}{code}"))
+        if !line.is_empty() {
+          v.push_back(Line::new(format!("{tab}{line}")));
+          line = String::new()
+        }
+        append_indented_lines(ctx, &mut v, &tab, code_lst)
+      }
+      let after_comment = new_rule_with_comment
+        .after_comment
+        .as_ref()
+        .filter(|comment| {
+          config.is_comment_aligned && comment.placement == CommentPlacement::EndOfLine
+        });
+      if let (Some(after_comment), Some(last)) = (after_comment, v.back()) {
+        let comment = format!(" {}", after_comment_format(&child_ctx, after_comment));
+        if let Some(code) = last.code.strip_suffix(&comment) {
+          comment_lines.push((v.len() - 1, code.len()))
+        }
       }
     }
+    if !line.is_empty() {
+      v.push_back(Line::new(format!("{tab}{line}")))
+    }
+    if config.is_comment_aligned {
+      align_after_comments(ctx, &mut v, &comment_lines)
+    }
+    if let Some(after_comment) = after_comment_opt {
+      v.push_back(Line::new(after_comment_format(ctx, after_comment)));
+      (v, true)
+    } else {
+      (v, is_last_exists_after_comment)
+    }
   }
-  if let Some(after_comment) = after_comment_opt {
-    v.push((ctx.oneline_comment_format)(after_comment.to_string()));
-    (v, true)
-  } else {
-    (v, false)
-  }
-}
 
-/// 貪欲法で分割する
-fn break_token_column(
-  ctx: &Context,
-  before_comments: &[String],
-  lst: &[(RuleWithComment, ColumnConfig)],
-  after_comment_opt: &Option<String>,
-) -> (Vec<String>, bool) {
-  let mut v = vec![];
-  if let Some(mut code_vec) = before_comments_format(ctx, before_comments) {
-    v.append(&mut code_vec)
-  }
-  let mut buf1 = String::new();
-  let mut buf1_after_spaces = 0;
-  let mut buf2 = String::new();
-  let mut buf2_after_spaces = 0;
-  let mut lst = lst.iter().peekable();
-  let mut is_last_exists_after_comment_global = false;
-  loop {
-    if let Some((rule_with_comment, config)) = lst.next() {
-      let (mut str_lst, is_last_exists_after_comment) =
-        code_format(&ctx.set_list_join_str(None), rule_with_comment);
-      is_last_exists_after_comment_global = is_last_exists_after_comment;
-      if str_lst.len() > 1 {
-        // 複数行
-        if !buf1.is_empty() {
-          let new_code_str = format!("{buf1}{}{buf2}", " ".repeat(buf1_after_spaces));
-          v.push(new_code_str);
-        }
-        v.append(&mut str_lst);
-        if is_last_exists_after_comment {
+  /// 貪欲法で分割する
+  /// `v`はフォーマット済みの前のコメントの行で、`indent_start`以降の行が継続行になる
+  fn break_token_column(
+    &self,
+    ctx: &Context,
+    mut v: VecDeque<Line>,
+    lst: &[(RuleWithComment, ColumnConfig)],
+    after_comment_opt: &Option<Comment>,
+    indent_start: usize,
+    formatted: Vec<(VecDeque<Line>, bool)>,
+  ) -> (VecDeque<Line>, bool) {
+    // 継続行は継続行のインデントの分だけ短くする
+    let len_max = |line_index: usize| {
+      if line_index < indent_start {
+        ctx.len_max()
+      } else {
+        ctx.continuation_len_max()
+      }
+    };
+    let mut buf1 = String::new();
+    let mut buf1_after_spaces = 0;
+    // buf1がそのまま出力する行の続きであるかどうか
+    let mut buf1_is_verbatim = false;
+    let mut buf2 = String::new();
+    let mut buf2_after_spaces = 0;
+    let mut lst = lst.iter().zip(formatted);
+    let mut is_last_exists_after_comment_global = false;
+    loop {
+      if let Some(((rule_with_comment, config), (mut str_lst, is_last_exists_after_comment))) =
+        lst.next()
+      {
+        is_last_exists_after_comment_global = is_last_exists_after_comment;
+        if is_line_break(rule_with_comment) {
+          // 強制改行・空行
+          if !buf2.is_empty() {
+            let new_code_str = format!("{buf1}{}{buf2}", " ".repeat(buf1_after_spaces));
+            v.push_back(Line {
+              code: new_code_str,
+              is_verbatim: buf1_is_verbatim,
+            });
+          } else if !buf1.is_empty() {
+            v.push_back(Line {
+              code: buf1,
+              is_verbatim: buf1_is_verbatim,
+            });
+          }
+          for code in str_lst {
+            push_indented_line(ctx, &mut v, "", code)
+          }
           buf1 = String::new();
           buf1_after_spaces = 0;
+          buf1_is_verbatim = false;
           buf2 = String::new();
           buf2_after_spaces = 0;
-        } else {
-          let last_code = v.pop().unwrap();
-          buf1 = last_code;
-          buf1_after_spaces = config.space_size.unwrap_or(1);
-          buf2 = String::new();
-          buf2_after_spaces = 0;
-        }
-      } else {
-        // 一行
-        let buf1_len = buf1.len();
-        let buf2_len = buf2.len();
-        // 一行であることが保証されている
-        let code_str = str_lst.join("");
-        let code_str_len = code_str.len();
-        if buf1_len + buf1_after_spaces + buf2_len + buf2_after_spaces + code_str_len
-          <= ctx.len_max()
-        {
-          // 行長が制限を超えなかったため、そのまま一行にする
-          match config.is_break {
-            Some(true) => {
-              // そのあとで絶対に改行
-              // 更新する
-              let new_code_str = if buf1.is_empty() {
-                code_str
+        } else if str_lst.len() > 1 {
+          // 複数行
+          let is_attached = is_verbatim_rule(rule_with_comment);
+          if !buf1.is_empty() {
+            let mut new_code_str = if buf2.is_empty() {
+              buf1.clone()
+            } else {
+              format!("{buf1}{}{buf2}", " ".repeat(buf1_after_spaces))
+            };
+            if is_attached {
+              // そのまま出力する文字列の先頭の行は、収まるなら直前の要素と同じ行に置く
+              let attached_code_str = if buf2.is_empty() {
+                format!("{buf1}{}{}", " ".repeat(buf1_after_spaces), str_lst[0].code)
               } else {
                 format!(
-                  "{buf1}{}{buf2}{}{code_str}",
-                  " ".repeat(buf1_after_spaces),
-                  " ".repeat(buf2_after_spaces)
+                  "{new_code_str}{}{}",
+                  " ".repeat(buf2_after_spaces),
+                  str_lst[0].code
                 )
               };
-              v.push(new_code_str);
-              buf1 = String::new();
-              buf1_after_spaces = 0;
-              buf2 = String::new();
-              buf2_after_spaces = 0;
-            }
-            Some(false) => {
-              // 改行不可ポイント
-              if buf1.is_empty() {
-                buf1 = code_str;
-                buf1_after_spaces = config.space_size.unwrap_or(1);
-              } else {
-                buf2.push_str(&code_str);
-                buf2_after_spaces = config.space_size.unwrap_or(1);
+              if ctx.width(&attached_code_str) <= len_max(v.len()) {
+                new_code_str = attached_code_str;
+                str_lst.pop_front();
               }
             }
-            None => {
-              // 改行可能ポイント
-              // 全てbuf1に入れてbuf2を初期化
-              if !buf1.is_empty() {
-                buf1.push_str(&" ".repeat(buf1_after_spaces));
-              }
-              buf1.push_str(&buf2);
-              if !buf2.is_empty() {
-                buf1.push_str(&" ".repeat(buf2_after_spaces));
-              }
-              buf1.push_str(&code_str);
-              buf1_after_spaces = config.space_size.unwrap_or(1);
-              buf2 = String::new();
-              buf2_after_spaces = 0;
-            }
+            v.push_back(Line {
+              code: new_code_str,
+              is_verbatim: buf1_is_verbatim,
+            });
           }
-        } else {
-          // 複数に改行しなければならない
-          if buf2_len == 0 {
-            // 直前が改行可能ポイントである
-            let new_line_code_str = if buf1.is_empty() {
-              buf2
-            } else {
-              format!("{buf1}{}{buf2}", " ".repeat(buf1_after_spaces))
-            };
-            v.push(new_line_code_str);
-            buf1 = code_str;
-            buf1_after_spaces = config.space_size.unwrap_or(1);
+          append_lines(&mut v, str_lst);
+          if is_last_exists_after_comment {
+            buf1 = String::new();
+            buf1_after_spaces = 0;
+            buf1_is_verbatim = false;
             buf2 = String::new();
             buf2_after_spaces = 0;
           } else {
-            // 直前が改行不可ポイントである
-            if buf2_len + buf2_after_spaces + code_str_len <= ctx.len_max() {
-              // buf2とcode_strをくっつけてよい
-              if !buf1.is_empty() {
-                v.push(buf1);
+            let last_line = v.pop_back().unwrap();
+            buf1 = last_line.code;
+            buf1_after_spaces = config.space_size.unwrap_or(1);
+            buf1_is_verbatim = last_line.is_verbatim;
+            buf2 = String::new();
+            buf2_after_spaces = 0;
+          }
+        } else {
+          // 一行
+          let buf1_len = ctx.width(&buf1);
+          let buf2_len = ctx.width(&buf2);
+          // 一行であることが保証されている
+          let code_str = join_code(&str_lst);
+          let code_str_len = ctx.width(&code_str);
+          if buf1_len + buf1_after_spaces + buf2_len + buf2_after_spaces + code_str_len
+            <= len_max(v.len())
+          {
+            // 行長が制限を超えなかったため、そのまま一行にする
+            match config.is_break {
+              Some(true) => {
+                // そのあとで絶対に改行
+                // 更新する
+                let new_code_str = if buf1.is_empty() {
+                  code_str
+                } else {
+                  format!(
+                    "{buf1}{}{buf2}{}{code_str}",
+                    " ".repeat(buf1_after_spaces),
+                    " ".repeat(buf2_after_spaces)
+                  )
+                };
+                v.push_back(Line {
+                  code: new_code_str,
+                  is_verbatim: buf1_is_verbatim,
+                });
+                buf1 = String::new();
+                buf1_after_spaces = 0;
+                buf1_is_verbatim = false;
+                buf2 = String::new();
+                buf2_after_spaces = 0;
               }
-              let new_line_code_str = format!("{buf2}{}{code_str}", " ".repeat(buf2_after_spaces));
-              v.push(new_line_code_str);
-              buf1 = String::new();
-              buf1_after_spaces = 0;
-              buf2 = String::new();
-              buf2_after_spaces = 0;
-            } else {
-              // buf2とcode_strをくっつけると行数オーバーする
-              // はみ出す量がより少ない方を取る
-              if buf1_len + buf1_after_spaces + buf2_len
-                > buf2_len + buf2_after_spaces + code_str_len
-              {
-                // buf1とbuf2をくっつけた方がはみ出しが少ない
+              Some(false) => {
+                // 改行不可ポイント
+                if buf1.is_empty() {
+                  buf1 = code_str;
+                  buf1_after_spaces = config.space_size.unwrap_or(1);
+                } else {
+                  buf2.push_str(&code_str);
+                  buf2_after_spaces = config.space_size.unwrap_or(1);
+                }
+              }
+              None => {
+                // 改行可能ポイント
+                // 全てbuf1に入れてbuf2を初期化
                 if !buf1.is_empty() {
-                  let new_line_code_str = format!("{buf1}{}{buf2}", " ".repeat(buf1_after_spaces));
-                  v.push(new_line_code_str);
+                  buf1.push_str(&" ".repeat(buf1_after_spaces));
                 }
-                buf1 = code_str;
+                buf1.push_str(&buf2);
+                if !buf2.is_empty() {
+                  buf1.push_str(&" ".repeat(buf2_after_spaces));
+                }
+                buf1.push_str(&code_str);
                 buf1_after_spaces = config.space_size.unwrap_or(1);
                 buf2 = String::new();
                 buf2_after_spaces = 0;
-              } else {
-                // buf2とcode_strをくっつけた方がはみ出しが少ない
+              }
+            }
+          } else {
+            // 複数に改行しなければならない
+            if buf2_len == 0 {
+              // 直前が改行可能ポイントである
+              // 行末には空白を残さない
+              // 最初の要素が収まらない場合は、空の行を出力しない
+              if !buf1.is_empty() || !buf2.is_empty() {
+                let new_line_code_str = if buf2.is_empty() {
+                  buf1
+                } else {
+                  format!("{buf1}{}{buf2}", " ".repeat(buf1_after_spaces))
+                };
+                v.push_back(Line {
+                  code: new_line_code_str,
+                  is_verbatim: buf1_is_verbatim,
+                });
+              }
+              buf1 = code_str;
+              buf1_after_spaces = config.space_size.unwrap_or(1);
+              buf1_is_verbatim = false;
+              buf2 = String::new();
+              buf2_after_spaces = 0;
+            } else {
+              // 直前が改行不可ポイントである
+              if buf2_len + buf2_after_spaces + code_str_len <= len_max(v.len() + 1) {
+                // buf2とcode_strをくっつけてよい
                 if !buf1.is_empty() {
-                  v.push(buf1);
+                  v.push_back(Line {
+                    code: buf1,
+                    is_verbatim: buf1_is_verbatim,
+                  });
                 }
                 let new_line_code_str =
                   format!("{buf2}{}{code_str}", " ".repeat(buf2_after_spaces));
-                v.push(new_line_code_str);
+                v.push_back(Line::new(new_line_code_str));
                 buf1 = String::new();
                 buf1_after_spaces = 0;
+                buf1_is_verbatim = false;
                 buf2 = String::new();
                 buf2_after_spaces = 0;
+              } else {
+                // buf2とcode_strをくっつけると行数オーバーする
+                // はみ出す量がより少ない方を取る
+                if buf1_len + buf1_after_spaces + buf2_len
+                  > buf2_len + buf2_after_spaces + code_str_len
+                {
+                  // buf1とbuf2をくっつけた方がはみ出しが少ない
+                  if !buf1.is_empty() {
+                    let new_line_code_str =
+                      format!("{buf1}{}{buf2}", " ".repeat(buf1_after_spaces));
+                    v.push_back(Line {
+                      code: new_line_code_str,
+                      is_verbatim: buf1_is_verbatim,
+                    });
+                  }
+                  buf1 = code_str;
+                  buf1_after_spaces = config.space_size.unwrap_or(1);
+                  buf1_is_verbatim = false;
+                  buf2 = String::new();
+                  buf2_after_spaces = 0;
+                } else {
+                  // buf2とcode_strをくっつけた方がはみ出しが少ない
+                  if !buf1.is_empty() {
+                    v.push_back(Line {
+                      code: buf1,
+                      is_verbatim: buf1_is_verbatim,
+                    });
+                  }
+                  let new_line_code_str =
+                    format!("{buf2}{}{code_str}", " ".repeat(buf2_after_spaces));
+                  v.push_back(Line::new(new_line_code_str));
+                  buf1 = String::new();
+                  buf1_after_spaces = 0;
+                  buf1_is_verbatim = false;
+                  buf2 = String::new();
+                  buf2_after_spaces = 0;
+                }
               }
             }
           }
         }
+      } else {
+        if buf2.is_empty() {
+          v.push_back(Line {
+            code: buf1,
+            is_verbatim: buf1_is_verbatim,
+          })
+        } else {
+          let new_line_code_str = format!("{buf1}{}{buf2}", " ".repeat(buf1_after_spaces));
+          v.push_back(Line {
+            code: new_line_code_str,
+            is_verbatim: buf1_is_verbatim,
+          })
+        }
+        break;
       }
+    }
+    if let Some(after_comment) = after_comment_opt {
+      if !is_last_exists_after_comment_global {
+        if let Some(last) = v.back_mut() {
+          // 最後にコードを追加する
+          last.code = format!("{} {}", last.code, after_comment_format(ctx, after_comment));
+        }
+      };
+      v.push_back(Line::new(after_comment_format(ctx, after_comment)));
+      (v, true)
     } else {
-      if buf2.is_empty() {
-        v.push(buf1)
+      (v, false)
+    }
+  }
+
+  /// 必ず改行が入る位置で区切った区間ごとに、`layout`で改行位置を決めて分割する
+  /// `v`はフォーマット済みの前のコメントの行で、`indent_start`以降の行が継続行になる
+  #[allow(clippy::too_many_arguments)]
+  fn break_token_column_by_segment(
+    &self,
+    ctx: &Context,
+    mut v: VecDeque<Line>,
+    lst: &[(RuleWithComment, ColumnConfig)],
+    after_comment_opt: &Option<Comment>,
+    indent_start: usize,
+    formatted: Vec<(VecDeque<Line>, bool)>,
+    layout: impl Fn(&Context, &[(String, &ColumnConfig)], bool) -> Vec<String>,
+  ) -> (VecDeque<Line>, bool) {
+    let mut segment: Vec<(String, &ColumnConfig)> = vec![];
+    // 区間の先頭がそのまま出力する行の続きであるかどうか
+    let mut is_segment_verbatim = false;
+    let mut is_last_exists_after_comment_global = false;
+    for ((rule_with_comment, config), (mut str_lst, is_last_exists_after_comment)) in
+      lst.iter().zip(formatted)
+    {
+      is_last_exists_after_comment_global = is_last_exists_after_comment;
+      if is_line_break(rule_with_comment) {
+        // 強制改行・空行
+        v.append(&mut segment_lines(
+          layout(ctx, &segment, v.len() < indent_start),
+          is_segment_verbatim,
+        ));
+        segment.clear();
+        is_segment_verbatim = false;
+        for code in str_lst {
+          push_indented_line(ctx, &mut v, "", code)
+        }
+        continue;
+      }
+      if str_lst.len() > 1 {
+        if is_verbatim_rule(rule_with_comment) {
+          // そのまま出力する文字列の先頭の行は、直前の要素と同じ区間に入れる
+          segment.push((str_lst.pop_front().unwrap().code, config));
+        }
+        // 複数行になる要素の直前では必ず改行する
+        v.append(&mut segment_lines(
+          layout(ctx, &segment, v.len() < indent_start),
+          is_segment_verbatim,
+        ));
+        segment.clear();
+        let last_line = str_lst.pop_back().unwrap();
+        append_lines(&mut v, str_lst);
+        segment.push((last_line.code, config));
+        is_segment_verbatim = last_line.is_verbatim;
       } else {
-        let new_line_code_str = format!("{buf1}{}{buf2}", " ".repeat(buf1_after_spaces));
-        v.push(new_line_code_str)
+        // 一行であることが保証されている
+        segment.push((join_code(&str_lst), config));
+      }
+      // 直後にコメントがある要素の後ろと、絶対に改行するポイントでは必ず改行する
+      if is_last_exists_after_comment || config.is_break == Some(true) {
+        v.append(&mut segment_lines(
+          layout(ctx, &segment, v.len() < indent_start),
+          is_segment_verbatim,
+        ));
+        segment.clear();
+        is_segment_verbatim = false;
+      }
+    }
+    v.append(&mut segment_lines(
+      layout(ctx, &segment, v.len() < indent_start),
+      is_segment_verbatim,
+    ));
+    if let Some(after_comment) = after_comment_opt {
+      let comment = after_comment_format(ctx, after_comment);
+      match v.back_mut() {
+        Some(last) if !is_last_exists_after_comment_global => {
+          last.code = format!("{} {comment}", last.code)
+        }
+        _ => v.push_back(Line::new(comment)),
       }
-      break;
+      (v, true)
+    } else {
+      (v, false)
     }
   }
-  if let Some(after_comment) = after_comment_opt {
-    if !is_last_exists_after_comment_global {
-      let last_code_opt = v.pop();
-      if let Some(last_code) = last_code_opt {
-        // 最後にコードを追加する
-        let code = format!(
-          "{last_code} {}",
-          (ctx.oneline_comment_format)(after_comment.to_string())
-        );
-        v.push(code);
+
+  /// Tableルールをフォーマットする
+  /// 揃えた結果一行の長さの制限を超えてしまう行は、揃えずに通常通りフォーマットする
+  /// `v`はフォーマット済みの前のコメントの行
+  #[allow(clippy::too_many_arguments)]
+  fn break_token_table(
+    &self,
+    ctx: &Context,
+    mut v: VecDeque<Line>,
+    table: &RuleWithComment,
+    rows: &[RuleWithComment],
+    after_comment_opt: &Option<Comment>,
+    formatted: impl IntoIterator<Item = (VecDeque<Line>, bool)>,
+    mut emit: Option<&mut dyn FnMut(Line)>,
+  ) -> (VecDeque<Line>, bool) {
+    let mut formatted = formatted.into_iter();
+    let mut is_last_exists_after_comment = false;
+    // `format_children`で揃えた結果を使う
+    let aligned_codes = self
+      .table_codes
+      .borrow_mut()
+      .remove(&(table as *const _))
+      .unwrap();
+    for (row, code_opt) in rows.iter().zip(aligned_codes) {
+      if let Some(emit) = emit.as_deref_mut() {
+        // 最後の行以外は後から書き換えない
+        v.drain(..v.len().saturating_sub(1)).for_each(emit)
       }
+      if let Some(code) = code_opt {
+        if let Some(mut code_vec) = before_comments_format(ctx, &row.before_comments) {
+          v.append(&mut code_vec)
+        }
+        if let Some(after_comment) = &row.after_comment {
+          let comment = after_comment_format(ctx, after_comment);
+          v.push_back(Line::new(format!("{code} {comment}")));
+          is_last_exists_after_comment = true;
+        } else {
+          v.push_back(Line::new(code));
+          is_last_exists_after_comment = false;
+        }
+      } else {
+        // 揃えられなかった行は`format_children`で通常通りフォーマットしている
+        let (code_vec, is_exists_after_comment) = formatted.next().unwrap();
+        append_lines(&mut v, code_vec);
+        is_last_exists_after_comment = is_exists_after_comment;
+      }
+    }
+    if let Some(after_comment) = after_comment_opt {
+      v.push_back(Line::new(after_comment_format(ctx, after_comment)));
+      (v, true)
+    } else {
+      (v, is_last_exists_after_comment)
+    }
+  }
+
+  /// 揃える対象の行を揃えた結果の一行の文字列
+  /// 揃えられない行と、揃えると一行の長さの制限を超えてしまう行は`None`になる
+  fn table_aligned_codes(&self, ctx: &Context, rows: &[RuleWithComment]) -> Vec<Option<String>> {
+    let cells_lst = rows
+      .iter()
+      .map(|row| self.table_row_cells(ctx, row))
+      .collect::<Vec<_>>();
+    let is_aligned_rows = cells_lst.iter().map(Option::is_some).collect::<Vec<_>>();
+    let aligned_code_lst = align_table_rows(ctx, &cells_lst, &is_aligned_rows);
+    // 制限を超えた行を揃える対象から外す
+    // 行を外しても残った行の幅は広がらないので、揃え直せば残った行はすべて制限に収まる
+    let is_fit_rows = aligned_code_lst
+      .iter()
+      .map(|code_opt| {
+        code_opt
+          .as_ref()
+          .is_some_and(|(_, width)| *width <= ctx.len_max())
+      })
+      .collect::<Vec<_>>();
+    let aligned_code_lst = if is_fit_rows == is_aligned_rows {
+      aligned_code_lst
+    } else {
+      align_table_rows(ctx, &cells_lst, &is_fit_rows)
     };
-    v.push((ctx.oneline_comment_format)(after_comment.to_string()));
+    aligned_code_lst
+      .into_iter()
+      .map(|code_opt| code_opt.map(|(code, _)| code))
+      .collect()
+  }
+
+  /// 揃える対象にできる行をセルごとの一行の文字列に分ける
+  /// `Column`でない行や、複数行になるセル・途中で直後のコメントがあるセルを含む行は揃えられないので`None`を返す
+  fn table_row_cells<'a>(
+    &self,
+    ctx: &Context,
+    row: &'a RuleWithComment,
+  ) -> Option<Vec<(String, &'a ColumnConfig)>> {
+    let Rule::Column(lst) = &row.rule else {
+      return None;
+    };
+    if row
+      .after_comment
+      .as_ref()
+      .is_some_and(|comment| comment.placement == CommentPlacement::OwnLine)
+    {
+      return None;
+    }
+    let mut cells = vec![];
+    for (i, (rule_with_comment, config)) in lst.iter().enumerate() {
+      if self.is_break_forced(rule_with_comment) || !self.is_flat(ctx, rule_with_comment) {
+        return None;
+      }
+      let (code, is_exists_after_comment) = self.flat_format(
+        &ctx.set_list_join_str(None).set_is_broken(false),
+        rule_with_comment,
+      );
+      if is_exists_after_comment && i < lst.len() - 1 {
+        return None;
+      }
+      cells.push((code, config));
+    }
+    Some(cells)
+  }
+}
+
+/// そのまま出力する文字列のルールかどうか
+fn is_verbatim_rule(rule_with_comment: &RuleWithComment) -> bool {
+  matches!(rule_with_comment.rule, Rule::Verbatim(_))
+    && rule_with_comment.before_comments.is_empty()
+}
+
+/// 強制改行・空行のように、前後で必ず改行するルールかどうか
+fn is_line_break(rule_with_comment: &RuleWithComment) -> bool {
+  matches!(
+    rule_with_comment.rule,
+    Rule::HardLine | Rule::BlankLine(_) | Rule::Dedent(_)
+  )
+}
+
+/// `List`の各要素をフォーマットするときの`Context`
+/// 要素直後のコメントの前に区切り文字を入れるため、区切り文字が必要な要素にだけ区切り文字を渡す
+fn list_child_contexts<'c>(
+  ctx: &Context<'c>,
+  join: &str,
+  config: &ListConfig,
+  lst: &[RuleWithComment],
+  is_hanging: bool,
+) -> Vec<Context<'c>> {
+  let is_leading = config.separator_position == SeparatorPosition::Leading;
+  let space_before = " ".repeat(config.space_before_separator.unwrap_or(0));
+  let trailing_join = format!("{space_before}{join}");
+  // 強制改行・空行を除いた最後の要素の位置
+  let last_index = lst.iter().rposition(|r| !is_line_break(r));
+  let child_ctx = if is_hanging {
+    ctx.set_is_broken(true)
+  } else {
+    ctx.increment_depth().set_is_broken(true)
+  };
+  lst
+    .iter()
+    .enumerate()
+    .map(|(i, child_rule_with_comment)| {
+      if is_line_break(child_rule_with_comment) || is_leading {
+        return child_ctx.set_list_join_str(None);
+      }
+      // 複数行になっているので、最後の要素の後ろには設定に従って区切り文字を入れる
+      let is_joined = last_index.is_some_and(|last_index| i < last_index)
+        || match config.trailing_separator {
+          TrailingSeparator::Never => false,
+          TrailingSeparator::Always | TrailingSeparator::Multiline => true,
+        };
+      child_ctx.set_list_join_str(is_joined.then(|| trailing_join.clone()))
+    })
+    .collect()
+}
+
+/// 複数行に分割する`Column`の`i`番目の要素をフォーマットするときの`Context`とフォーマットのしかた
+/// 最初の要素は`Column`の最初の行から始まるので、継続行のインデントの分の幅を空けない
+/// 継続行にある`Column`の要素は既に継続行のインデントの分の幅を空けているので、さらには空けない
+fn column_child_context<'c>(
+  ctx: &Context<'c>,
+  group_mode: GroupMode,
+  i: usize,
+) -> (Context<'c>, GroupMode) {
+  let is_continued = group_mode == GroupMode::Continued;
+  let child_ctx = if i == 0 || is_continued {
+    ctx.set_list_join_str(None)
+  } else {
+    ctx
+      .add_offset(ctx.continuation_indent)
+      .set_list_join_str(None)
+  };
+  let child_group_mode = if i == 0 && !is_continued {
+    GroupMode::Auto
+  } else {
+    GroupMode::Continued
+  };
+  (child_ctx, child_group_mode)
+}
+
+/// 一行に並べるときに結果が必要な子と、その子を並べるときの`Context`を出力される順に並べる
+fn flat_children<'c, 'r>(
+  ctx: &Context<'c>,
+  rule_with_comment: &'r RuleWithComment,
+) -> Vec<(Context<'c>, &'r RuleWithComment)> {
+  match &rule_with_comment.rule {
+    Rule::AST(child_rule_with_comment) => vec![(ctx.clone(), child_rule_with_comment)],
+    Rule::Paren(_, child_rule_with_comment, _, _) => {
+      vec![(ctx.set_list_join_str(None), child_rule_with_comment)]
+    }
+    Rule::List(join, lst, config) => {
+      let trailing_join = format!(
+        "{}{join}",
+        " ".repeat(config.space_before_separator.unwrap_or(0))
+      );
+      let is_trailing_joined = config.separator_position == SeparatorPosition::Trailing
+        && config.trailing_separator == TrailingSeparator::Always;
+      lst
+        .iter()
+        .enumerate()
+        .map(|(i, child_rule_with_comment)| {
+          // 要素直後のコメントの前に区切り文字を入れるため、区切り文字が必要な要素にだけ区切り文字を渡す
+          let is_joined = i < lst.len() - 1 || is_trailing_joined;
+          (
+            ctx.set_list_join_str(is_joined.then(|| trailing_join.clone())),
+            child_rule_with_comment,
+          )
+        })
+        .collect()
+    }
+    Rule::Column(lst) => lst
+      .iter()
+      .map(|(child_rule_with_comment, _)| (ctx.set_list_join_str(None), child_rule_with_comment))
+      .collect(),
+    Rule::Table(rows) => rows
+      .first()
+      .map(|row| (ctx.set_list_join_str(None), row))
+      .into_iter()
+      .collect(),
+    Rule::IfBreak(break_rule_with_comment, flat_rule_with_comment) => {
+      if ctx.is_broken {
+        vec![(ctx.clone(), break_rule_with_comment)]
+      } else {
+        vec![(ctx.clone(), flat_rule_with_comment)]
+      }
+    }
+    Rule::Raw(_) | Rule::Verbatim(_) | Rule::HardLine | Rule::BlankLine(_) | Rule::Dedent(_) => {
+      vec![]
+    }
+  }
+}
+
+/// `flat_children`で並べた子を一行に並べた結果を使って、前のコメントを除いて一行に並べる
+fn flat_combine(
+  ctx: &Context,
+  rule_with_comment: &RuleWithComment,
+  formatted: Vec<(String, bool)>,
+) -> (String, bool) {
+  // 区切り文字を直後のコメントの前に入れるかどうか
+  let mut is_joiner_used = true;
+  let mut formatted = formatted.into_iter();
+  let (code, is_exists_after_comment) = match &rule_with_comment.rule {
+    Rule::AST(_) | Rule::IfBreak(..) => formatted.next().unwrap(),
+    Rule::Raw(str) | Rule::Verbatim(str) => (str.to_string(), false),
+    Rule::Paren(open, _, close, _) => {
+      let (code, _) = formatted.next().unwrap();
+      (format!("{open}{code}{close}"), false)
+    }
+    Rule::List(join, lst, config) => {
+      is_joiner_used = false;
+      let space_before = " ".repeat(config.space_before_separator.unwrap_or(0));
+      let space_after = " ".repeat(config.space_after_separator.unwrap_or(1));
+      let trailing_join = format!("{space_before}{join}");
+      let is_trailing_joined = config.separator_position == SeparatorPosition::Trailing
+        && config.trailing_separator == TrailingSeparator::Always;
+      let mut is_last_exists_after_comment = false;
+      let mut code = lst
+        .iter()
+        .zip(formatted)
+        .enumerate()
+        .map(
+          |(i, (child_rule_with_comment, (code, is_exists_after_comment)))| {
+            if i == lst.len() - 1 {
+              // 最後の要素の直後にコメントがあった場合にフラグをたてる
+              is_last_exists_after_comment = is_exists_after_comment
+            }
+            match inline_comments_format(ctx, config, child_rule_with_comment) {
+              Some(comments) => format!("{comments} {code}"),
+              None => code,
+            }
+          },
+        )
+        .collect::<Vec<_>>()
+        .join(&format!("{trailing_join}{space_after}"));
+      if !lst.is_empty() && is_trailing_joined && !is_last_exists_after_comment {
+        code.push_str(&trailing_join);
+      }
+      (code, is_last_exists_after_comment)
+    }
+    Rule::Column(lst) => {
+      is_joiner_used = false;
+      let mut code = String::new();
+      let mut is_last_exists_after_comment = false;
+      for (i, ((_, config), (child_code, is_exists_after_comment))) in
+        lst.iter().zip(formatted).enumerate()
+      {
+        code.push_str(&child_code);
+        if i < lst.len() - 1 {
+          code.push_str(&" ".repeat(config.space_size.unwrap_or(1)));
+        }
+        is_last_exists_after_comment = is_exists_after_comment;
+      }
+      (code, is_last_exists_after_comment)
+    }
+    Rule::Table(_) => formatted.next().unwrap_or((String::new(), false)),
+    Rule::HardLine | Rule::BlankLine(_) | Rule::Dedent(_) => (String::new(), false),
+  };
+  if let Some(after_comment) = &rule_with_comment.after_comment {
+    let comment = after_comment_format(ctx, after_comment);
+    match &ctx.list_join_str {
+      Some(joiner) if is_joiner_used => (format!("{code}{joiner} {comment}"), true),
+      _ => (format!("{code} {comment}"), true),
+    }
+  } else {
+    (code, is_exists_after_comment)
+  }
+}
+
+/// 連続する行の直後のコメントの開始位置を揃える
+/// `comment_lines`は直後のコメントで終わる行の位置と、その行のコメントの前の部分の長さ
+/// 揃えると一行の長さの制限を超えてしまう行は揃えない
+fn align_after_comments(ctx: &Context, v: &mut VecDeque<Line>, comment_lines: &[(usize, usize)]) {
+  let mut start = 0;
+  while start < comment_lines.len() {
+    let mut end = start + 1;
+    while end < comment_lines.len() && comment_lines[end].0 == comment_lines[end - 1].0 + 1 {
+      end += 1
+    }
+    // 行の位置、コメントの前の部分の長さ、コメントの前の部分の幅、コメントの幅
+    let mut run = comment_lines[start..end]
+      .iter()
+      .map(|&(i, code_len)| {
+        let (code, comment) = v[i].code.split_at(code_len);
+        (i, code_len, ctx.width(code), ctx.width(comment))
+      })
+      .collect::<Vec<_>>();
+    let column = loop {
+      let column = run.iter().map(|(_, _, width, _)| *width).max().unwrap_or(0);
+      let len = run.len();
+      run.retain(|(_, _, _, comment_width)| column + comment_width <= ctx.len_max());
+      if run.len() == len {
+        break column;
+      }
+    };
+    for (i, code_len, width, _) in run {
+      v[i].code.insert_str(code_len, &" ".repeat(column - width))
+    }
+    start = end;
+  }
+}
+
+/// 複数行に分割した`List`の行のうち、後の要素によって書き換えられることのない先頭の行を`emit`に渡して取り除く
+/// 最後の行まで続く直後のコメントの行、末尾の空行と最後の行は書き換えることがあるので残す
+fn emit_list_lines(
+  ctx: &Context,
+  config: &ListConfig,
+  v: &mut VecDeque<Line>,
+  comment_lines: &mut Vec<(usize, usize)>,
+  emit: &mut dyn FnMut(Line),
+) {
+  // 最後の行まで続いている直後のコメントの行は、次の要素の行と揃えることがある
+  let mut open_start = comment_lines.len();
+  while open_start > 0
+    && comment_lines[open_start - 1].0 + comment_lines.len() - open_start + 1 == v.len()
+  {
+    open_start -= 1
+  }
+  if config.is_comment_aligned {
+    align_after_comments(ctx, v, &comment_lines[..open_start])
+  }
+  comment_lines.drain(..open_start);
+  let blank_lines = v
+    .iter()
+    .rev()
+    .take_while(|line| !line.is_verbatim && line.code.is_empty())
+    .count();
+  let len = comment_lines
+    .first()
+    .map_or(v.len(), |&(i, _)| i)
+    .min(v.len() - blank_lines)
+    .min(v.len().saturating_sub(1));
+  v.drain(..len).for_each(emit);
+  for (i, _) in comment_lines.iter_mut() {
+    *i -= len
+  }
+}
+
+/// 強制改行・空行をフォーマットする
+/// 空行の後ろにコメントを置く
+fn line_break_format(
+  ctx: &Context,
+  rule_with_comment: &RuleWithComment,
+  blank_lines: usize,
+) -> (VecDeque<Line>, bool) {
+  let mut v = VecDeque::from(vec![Line::default(); blank_lines.min(ctx.max_blank_lines)]);
+  if let Some(mut code_vec) = before_comments_format(ctx, &rule_with_comment.before_comments) {
+    v.append(&mut code_vec)
+  }
+  if let Some(after_comment) = &rule_with_comment.after_comment {
+    v.push_back(Line::new(after_comment_format(ctx, after_comment)));
     (v, true)
   } else {
     (v, false)
   }
 }
 
-fn before_comments_format(ctx: &Context, comments: &[String]) -> Option<Vec<String>> {
-  if comments.is_empty() {
-    None
-  } else if comments.len() == 1 {
-    Some(vec![(ctx.oneline_comment_format)(comments[0].clone())])
+/// 行頭にインデントを付けて追加する
+/// 空行にはインデントを付けず、空行が`Context::max_blank_lines`を超えて連続しないようにする
+/// そのまま出力する行は空行であっても手を加えない
+fn push_indented_line(ctx: &Context, v: &mut VecDeque<Line>, tab: &str, line: Line) {
+  if !line.is_verbatim && line.code.is_empty() {
+    let blank_lines = v
+      .iter()
+      .rev()
+      .take_while(|line| !line.is_verbatim && line.code.is_empty())
+      .count();
+    if blank_lines < ctx.max_blank_lines {
+      v.push_back(Line::default())
+    }
   } else {
-    Some((ctx.block_comment_format)(ctx.clone(), comments.to_vec()))
+    v.push_back(line.indent(tab))
+  }
+}
+
+/// 子の行に行頭のインデントを付けて追加する
+/// 子の行の方が多い場合は、子の`VecDeque`をそのまま使う
+/// 子の中の空行は子をフォーマットしたときに詰めてあるので、境目で連続する空行だけを詰める
+fn append_indented_lines(
+  ctx: &Context,
+  v: &mut VecDeque<Line>,
+  tab: &str,
+  mut lines: VecDeque<Line>,
+) {
+  if v.len() >= lines.len() {
+    for line in lines {
+      push_indented_line(ctx, v, tab, line)
+    }
+    return;
+  }
+  let is_blank = |line: &Line| !line.is_verbatim && line.code.is_empty();
+  let blank_lines = v.iter().rev().take_while(|line| is_blank(line)).count();
+  let leading_blank_lines = lines.iter().take_while(|line| is_blank(line)).count();
+  let kept_blank_lines = leading_blank_lines.min(ctx.max_blank_lines.saturating_sub(blank_lines));
+  lines.drain(..leading_blank_lines - kept_blank_lines);
+  if !tab.is_empty() {
+    for line in lines.iter_mut() {
+      *line = std::mem::take(line).indent(tab)
+    }
+  }
+  append_lines(v, lines)
+}
+
+/// 区間を分割した結果を行にする
+/// 区間の先頭がそのまま出力する行の続きである場合は、最初の行もそのまま出力する
+fn segment_lines(codes: Vec<String>, is_verbatim: bool) -> VecDeque<Line> {
+  codes
+    .into_iter()
+    .enumerate()
+    .map(|(i, code)| Line {
+      code,
+      is_verbatim: is_verbatim && i == 0,
+    })
+    .collect()
+}
+
+/// 一行分のコスト
+/// フィールドの順に辞書式で比較する
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+struct ColumnCost {
+  /// 一行の長さの制限からはみ出した文字数と、改行したポイントのペナルティの合計
+  penalty: usize,
+  /// 行数
+  lines: usize,
+  /// 最終行以外の行末の余白の二乗和
+  raggedness: usize,
+}
+
+impl ColumnCost {
+  /// `penalty`は行末で改行することのペナルティ
+  fn line(len_max: usize, width: usize, penalty: usize, is_last: bool) -> Self {
+    let slack = len_max.saturating_sub(width);
+    ColumnCost {
+      penalty: width.saturating_sub(len_max) + penalty,
+      lines: 1,
+      raggedness: if is_last { 0 } else { slack * slack },
+    }
+  }
+  fn add(&self, other: &Self) -> Self {
+    ColumnCost {
+      penalty: self.penalty + other.penalty,
+      lines: self.lines + other.lines,
+      raggedness: self.raggedness + other.raggedness,
+    }
+  }
+}
+
+/// 改行位置の組み合わせのうち、コストが最小となるものを動的計画法で選んで分割する
+/// 区間の途中にある`is_break`が`Some(true)`の要素は考慮しない
+/// `is_first`がtrueのとき、区間の最初の行は`Column`の最初の行として継続行のインデントを付けずに数える
+fn layout_column_segment(
+  ctx: &Context,
+  segment: &[(String, &ColumnConfig)],
+  is_first: bool,
+) -> Vec<String> {
+  if segment.is_empty() {
+    return vec![];
+  }
+  let len = segment.len();
+  let is_breakable = |i: usize| segment[i].1.is_break != Some(false);
+  let space_size = |i: usize| segment[i].1.space_size.unwrap_or(1);
+  // best[j]: 先頭からj個の要素を並べて、j個目の直後で改行したときの最小コストと、その行の先頭の位置
+  let mut best: Vec<Option<(ColumnCost, usize)>> = vec![None; len + 1];
+  best[0] = Some((ColumnCost::default(), 0));
+  for j in 1..=len {
+    if j < len && !is_breakable(j - 1) {
+      continue;
+    }
+    let mut width = 0;
+    for i in (0..j).rev() {
+      width += ctx.width(&segment[i].0);
+      if i < j - 1 {
+        width += space_size(i);
+      }
+      if i > 0 && !is_breakable(i - 1) {
+        continue;
+      }
+      if let Some((cost, _)) = best[i] {
+        let len_max = if i == 0 && is_first {
+          ctx.len_max()
+        } else {
+          ctx.continuation_len_max()
+        };
+        let is_last = j == len;
+        // 区間の最後では必ず改行するので、ペナルティは数えない
+        let penalty = if is_last { 0 } else { segment[j - 1].1.penalty };
+        let new_cost = cost.add(&ColumnCost::line(len_max, width, penalty, is_last));
+        if best[j].is_none_or(|(c, _)| new_cost < c) {
+          best[j] = Some((new_cost, i));
+        }
+      }
+    }
+  }
+  let mut ranges = vec![];
+  let mut j = len;
+  while j > 0 {
+    let (_, i) = best[j].unwrap();
+    ranges.push(i..j);
+    j = i;
+  }
+  ranges
+    .into_iter()
+    .rev()
+    .map(|range| join_column_segment(&segment[range]))
+    .collect()
+}
+
+/// 改行可能なポイントですべて改行する
+fn split_column_segment(segment: &[(String, &ColumnConfig)]) -> Vec<String> {
+  if segment.is_empty() {
+    return vec![];
+  }
+  let mut v = vec![];
+  let mut start = 0;
+  for (i, (_, config)) in segment.iter().enumerate() {
+    if config.is_break != Some(false) || i == segment.len() - 1 {
+      v.push(join_column_segment(&segment[start..=i]));
+      start = i + 1;
+    }
+  }
+  v
+}
+
+/// 区間を改行せずに一行に並べる
+fn join_column_segment(segment: &[(String, &ColumnConfig)]) -> String {
+  let mut code = String::new();
+  for (i, (str, config)) in segment.iter().enumerate() {
+    code.push_str(str);
+    if i < segment.len() - 1 {
+      code.push_str(&" ".repeat(config.space_size.unwrap_or(1)));
+    }
+  }
+  code
+}
+
+/// `is_aligned_rows`がtrueの行のセルを揃えて一行の文字列にし、その幅と組にする
+fn align_table_rows(
+  ctx: &Context,
+  cells_lst: &[Option<Vec<(String, &ColumnConfig)>>],
+  is_aligned_rows: &[bool],
+) -> Vec<Option<(String, usize)>> {
+  // 揃えた行とその幅
+  let mut code_lst = cells_lst
+    .iter()
+    .zip(is_aligned_rows)
+    .map(|(cells_opt, is_aligned)| {
+      cells_opt
+        .as_ref()
+        .filter(|_| *is_aligned)
+        .map(|_| (String::new(), 0))
+    })
+    .collect::<Vec<_>>();
+  let cells_len_max = cells_lst.iter().flatten().map(Vec::len).max().unwrap_or(0);
+  for k in 0..cells_len_max {
+    // k番目のセルを置く位置を決める
+    let natural_start = |width: usize, cells: &[(String, &ColumnConfig)]| {
+      if k == 0 {
+        0
+      } else {
+        width + cells[k - 1].1.space_size.unwrap_or(1)
+      }
+    };
+    let aligned_start = code_lst
+      .iter()
+      .zip(cells_lst)
+      .filter_map(|(code_opt, cells_opt)| code_opt.as_ref().zip(cells_opt.as_ref()))
+      .filter(|(_, cells)| cells.get(k).is_some_and(|(_, config)| config.is_aligned))
+      .map(|((_, width), cells)| natural_start(*width, cells))
+      .max();
+    for (code_opt, cells_opt) in code_lst.iter_mut().zip(cells_lst) {
+      if let (Some((code, width)), Some(cells)) = (code_opt, cells_opt) {
+        if let Some((cell, config)) = cells.get(k) {
+          let start = if config.is_aligned {
+            aligned_start.unwrap_or(0)
+          } else {
+            natural_start(*width, cells)
+          };
+          let padding = start.saturating_sub(*width);
+          code.push_str(&" ".repeat(padding));
+          code.push_str(cell);
+          *width += padding + ctx.width(cell);
+        }
+      }
+    }
+  }
+  code_lst
+}
+
+/// 要素の前のコメントをフォーマットする
+/// 行コメントは一行ずつ、ブロックコメントは一つずつ、ドキュメントコメントは連続するものをまとめてフォーマットする
+fn before_comments_format(ctx: &Context, comments: &[Comment]) -> Option<VecDeque<Line>> {
+  if comments.is_empty() {
+    return None;
+  }
+  let mut v = vec![];
+  let mut doc_lines = vec![];
+  for (i, comment) in comments.iter().enumerate() {
+    let mut lines = comment
+      .text
+      .split('\n')
+      .map(str::to_string)
+      .collect::<Vec<_>>();
+    if ctx.is_comment_reflowed {
+      lines = reflow_comment_lines(ctx, lines, comment_prefix_width(ctx, comment.kind))
+    }
+    let lines = lines.into_iter();
+    match comment.kind {
+      CommentKind::Line => v.extend(lines.map(|line| (ctx.oneline_comment_format)(line))),
+      CommentKind::Block => v.append(&mut (ctx.block_comment_format)(
+        ctx.clone(),
+        lines.collect(),
+      )),
+      CommentKind::Doc => doc_lines.extend(lines),
+    }
+    let is_doc_end = comments
+      .get(i + 1)
+      .is_none_or(|next| next.kind != CommentKind::Doc);
+    if !doc_lines.is_empty() && is_doc_end {
+      v.append(&mut (ctx.doc_comment_format)(
+        ctx.clone(),
+        std::mem::take(&mut doc_lines),
+      ));
+    }
+  }
+  Some(v.into_iter().map(Line::new).collect())
+}
+
+/// コメント記号などの、コメントの本文以外の部分の幅
+/// 空の本文をフォーマットしたときの幅とみなす
+fn comment_prefix_width(ctx: &Context, kind: CommentKind) -> usize {
+  let lines = match kind {
+    CommentKind::Line => vec![(ctx.oneline_comment_format)(String::new())],
+    CommentKind::Block => (ctx.block_comment_format)(ctx.clone(), vec![String::new()]),
+    CommentKind::Doc => (ctx.doc_comment_format)(ctx.clone(), vec![String::new()]),
+  };
+  lines.iter().map(|line| ctx.width(line)).max().unwrap_or(0)
+}
+
+/// 一行の長さの制限に収まるように、コメントの本文の行を単語の区切りで折り返す
+/// 折り返した行には元の行の先頭の空白を付け、同じ行に残る単語の間の空白はそのまま残す
+/// 単語の途中では折り返さないので、URLのような長い単語はそのまま残る
+/// "```"で囲まれたコードブロックの中の行は折り返さない
+fn reflow_comment_lines(ctx: &Context, lines: Vec<String>, prefix_width: usize) -> Vec<String> {
+  let len_max = ctx.len_max().saturating_sub(prefix_width);
+  let mut v = vec![];
+  let mut is_code_block = false;
+  for line in lines {
+    if line.trim_start().starts_with("```") {
+      is_code_block = !is_code_block;
+      v.push(line);
+      continue;
+    }
+    if is_code_block || ctx.width(&line) <= len_max {
+      v.push(line);
+      continue;
+    }
+    let indent = &line[..line.len() - line.trim_start().len()];
+    let mut new_line = indent.to_string();
+    let mut rest = &line[indent.len()..];
+    while let Some(word_start) = rest.find(|c: char| !c.is_whitespace()) {
+      // 単語とその前の空白
+      let (space, tail) = rest.split_at(word_start);
+      let word_end = tail.find(char::is_whitespace).unwrap_or(tail.len());
+      let (word, tail) = tail.split_at(word_end);
+      rest = tail;
+      if new_line.len() > indent.len() {
+        if ctx.width(&new_line) + ctx.width(space) + ctx.width(word) <= len_max {
+          new_line.push_str(space)
+        } else {
+          v.push(std::mem::replace(&mut new_line, indent.to_string()))
+        }
+      }
+      new_line.push_str(word)
+    }
+    v.push(new_line)
+  }
+  v
+}
+
+/// `ListConfig::is_block_comment_inlined`が設定されたリストの要素の前に、同じ行に並べるコメント
+/// 前のコメントが一行のブロックコメントだけである場合にだけ`Some`を返す
+fn inline_comments_format(
+  ctx: &Context,
+  config: &ListConfig,
+  rule_with_comment: &RuleWithComment,
+) -> Option<String> {
+  let comments = &rule_with_comment.before_comments;
+  let is_inlined = config.is_block_comment_inlined
+    && !comments.is_empty()
+    && comments
+      .iter()
+      .all(|comment| comment.kind == CommentKind::Block && !comment.text.contains('\n'));
+  is_inlined.then(|| {
+    comments
+      .iter()
+      .map(|comment| after_comment_format(ctx, comment))
+      .collect::<Vec<_>>()
+      .join(" ")
+  })
+}
+
+/// 要素の直後のコメントや同じ行に並べるコメントを一行の文字列にフォーマットする
+/// 複数行にフォーマットされた場合は空白で繋げる
+fn after_comment_format(ctx: &Context, comment: &Comment) -> String {
+  let lines = comment.text.split('\n').map(str::to_string);
+  match comment.kind {
+    CommentKind::Line => lines
+      .map(|line| (ctx.oneline_comment_format)(line))
+      .collect::<Vec<_>>()
+      .join(" "),
+    CommentKind::Block => (ctx.block_comment_format)(ctx.clone(), lines.collect()).join(" "),
+    CommentKind::Doc => (ctx.doc_comment_format)(ctx.clone(), lines.collect()).join(" "),
   }
 }
@@ -0,0 +1,55 @@
+//! 文字列の端末上での表示幅を数える
+
+mod table;
+
+const ZERO_WIDTH_JOINER: char = '\u{200D}';
+
+fn is_in_table(c: char, table: &[(u32, u32)]) -> bool {
+  let c = c as u32;
+  table
+    .binary_search_by(|(start, end)| {
+      if c < *start {
+        std::cmp::Ordering::Greater
+      } else if *end < c {
+        std::cmp::Ordering::Less
+      } else {
+        std::cmp::Ordering::Equal
+      }
+    })
+    .is_ok()
+}
+
+/// 一文字の表示幅
+/// East Asian WidthがWideとFullwidthの文字は2、結合文字と制御文字は0、
+/// Ambiguousの文字は`ambiguous_width`として数える
+pub fn char_width(c: char, ambiguous_width: usize) -> usize {
+  if c.is_ascii() {
+    if c.is_ascii_control() {
+      0
+    } else {
+      1
+    }
+  } else if c.is_control() || is_in_table(c, table::ZERO_WIDTH) {
+    0
+  } else if is_in_table(c, table::WIDE) {
+    2
+  } else if is_in_table(c, table::AMBIGUOUS) {
+    ambiguous_width
+  } else {
+    1
+  }
+}
+
+/// 文字列の表示幅
+/// ゼロ幅接合子で繋がれた絵文字は一つの絵文字として表示されるため、接合子の直後の文字は数えない
+pub fn str_width(s: &str, ambiguous_width: usize) -> usize {
+  let mut width = 0;
+  let mut is_joined = false;
+  for c in s.chars() {
+    if !is_joined {
+      width += char_width(c, ambiguous_width)
+    }
+    is_joined = c == ZERO_WIDTH_JOINER;
+  }
+  width
+}
@@ -1,6 +1,6 @@
 extern crate code_format;
 
-use code_format::{dynamic::*, ColumnConfig};
+use code_format::{deep_drop::drop_deep, dynamic::*, ColumnConfig};
 use std::collections::HashMap;
 
 #[test]
@@ -847,3 +847,34 @@ fn check_confirmed_2() {
   };
   assert_eq!(Some(&after), list1_after);
 }
+
+#[test]
+fn check_listedrule_to_rule_3() {
+  // 深くネストしたリストから木を組み立ててもスタックがあふれない
+  let depth = 20000;
+  let mut listedrules = vec![];
+  for _ in 0..depth {
+    listedrules.push(ListedRule::Open(OpenRule::Paren(
+      None,
+      "(".to_string(),
+      vec![],
+    )));
+  }
+  listedrules.push(ListedRule::Raw("x".to_string()));
+  for _ in 0..depth {
+    listedrules.push(ListedRule::Close(CloseRule::Paren(")".to_string(), None)));
+  }
+  let (rule_with_comment, column_config_opt, count) = listedrule_to_rule(&listedrules, 0);
+  assert_eq!(listedrules.len(), count);
+  assert_eq!(None, column_config_opt);
+  let mut rule = &rule_with_comment.rule;
+  let mut parens = 0;
+  while let Rule::Paren(None, open_str, content, close_str) = rule {
+    assert_eq!(("(", ")"), (open_str.as_str(), close_str.as_str()));
+    rule = &content.rule;
+    parens += 1;
+  }
+  assert_eq!(depth, parens);
+  assert_eq!(&Rule::Raw("x".to_string()), rule);
+  drop_deep(rule_with_comment);
+}
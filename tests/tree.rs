@@ -1,13 +1,21 @@
 extern crate code_format;
 
 use code_format::{
-  tree::{code_format, Ast2RuleWithComment, Rule, RuleWithComment},
-  ColumnConfig, Context,
+  deep_drop::drop_deep,
+  tree::{
+    code_format, code_format_to_fmt, code_format_to_io, Ast2RuleWithComment, Comment,
+    CommentPlacement, Rule, RuleWithComment,
+  },
+  ColumnConfig, ColumnLayout, Context, IndentStyle, ListConfig, ListLayout, ParenConfig,
+  SeparatorPosition, TrailingSeparator,
 };
 
 fn oneline_comment_format(s: String) -> String {
   format!("// {s}")
 }
+fn doc_comment_format(_ctx: Context, s: Vec<String>) -> Vec<String> {
+  s.into_iter().map(|s| format!("/// {s}")).collect()
+}
 fn block_comment_format(_ctx: Context, s: Vec<String>) -> Vec<String> {
   let mut v = vec![String::from("/*")];
   for s in s {
@@ -20,12 +28,21 @@ fn block_comment_format(_ctx: Context, s: Vec<String>) -> Vec<String> {
 fn make_ctx<'a>() -> Context<'a> {
   Context {
     depth: 0,
+    offset: 0,
     tab_spaces: 2,
+    indent_style: IndentStyle::Space,
+    continuation_indent: 0,
     line_width: 35,
     break_str: String::from("\n"),
+    max_blank_lines: 1,
     list_join_str: None,
+    is_broken: false,
+    column_layout: ColumnLayout::Greedy,
+    ambiguous_width: 1,
+    is_comment_reflowed: false,
     oneline_comment_format: &oneline_comment_format,
     block_comment_format: &block_comment_format,
+    doc_comment_format: &doc_comment_format,
   }
 }
 
@@ -44,20 +61,29 @@ enum Test {
 fn make_rule_with_comment_none(rule: Rule) -> RuleWithComment {
   RuleWithComment {
     before_comments: vec![],
-    rule: rule,
+    rule,
     after_comment: None,
   }
 }
 
+/// 一つだけのコメントは行コメントに、複数のコメントはまとめて一つのブロックコメントにする
+fn make_comments(comments: Vec<String>) -> Vec<Comment> {
+  match comments.len() {
+    0 => vec![],
+    1 => vec![Comment::line(&comments[0])],
+    _ => vec![Comment::block(&comments.join("\n"))],
+  }
+}
+
 fn make_rule_with_comment(
   before_comments: Vec<String>,
   rule: Rule,
   after_comment: Option<String>,
 ) -> RuleWithComment {
   RuleWithComment {
-    before_comments,
-    rule: rule,
-    after_comment,
+    before_comments: make_comments(before_comments),
+    rule,
+    after_comment: after_comment.map(|comment| Comment::line(&comment)),
   }
 }
 
@@ -69,6 +95,7 @@ impl Ast2RuleWithComment for Test {
         "(".to_string(),
         Box::new(make_rule_with_comment_none(Rule::Raw(float.to_string()))),
         ")".to_string(),
+        ParenConfig::default(),
       )),
       Test::AWithComment(before_comments, int, after_comment) => make_rule_with_comment(
         before_comments.clone(),
@@ -81,6 +108,7 @@ impl Ast2RuleWithComment for Test {
           "(".to_string(),
           Box::new(make_rule_with_comment_none(Rule::Raw(float.to_string()))),
           ")".to_string(),
+          ParenConfig::default(),
         ),
         after_comment.clone(),
       ),
@@ -91,6 +119,7 @@ impl Ast2RuleWithComment for Test {
           "<".to_string(),
           Box::new(rule),
           ">".to_string(),
+          ParenConfig::default(),
         ))
       }
       Test::C(lst) => make_rule_with_comment_none(Rule::Paren(
@@ -98,8 +127,10 @@ impl Ast2RuleWithComment for Test {
         Box::new(make_rule_with_comment_none(Rule::List(
           ",".to_string(),
           lst.iter().map(|t| t.to_rule()).collect::<Vec<_>>(),
+          ListConfig::default(),
         ))),
         "]".to_string(),
+        ParenConfig::default(),
       )),
       Test::D(before_comments, lst) => make_rule_with_comment(
         before_comments.clone(),
@@ -108,8 +139,10 @@ impl Ast2RuleWithComment for Test {
           Box::new(make_rule_with_comment_none(Rule::List(
             ",".to_string(),
             lst.iter().map(|t| t.to_rule()).collect::<Vec<_>>(),
+            ListConfig::default(),
           ))),
           "]".to_string(),
+          ParenConfig::default(),
         ),
         None,
       ),
@@ -137,6 +170,7 @@ impl Ast2RuleWithComment for Test {
                   inner.to_rule(),
                 )))),
                 "}".to_string(),
+                ParenConfig::default(),
               )),
               default_cc.set_is_break(None),
             ),
@@ -149,7 +183,7 @@ impl Ast2RuleWithComment for Test {
 }
 
 #[test]
-
+#[allow(clippy::approx_constant, clippy::useless_format)]
 fn check1() {
   let test = Test::B(3.14);
   let ok_str = format!("(3.14)");
@@ -157,13 +191,14 @@ fn check1() {
 }
 
 #[test]
-
+#[allow(clippy::useless_format)]
 fn check2() {
   let test = make_rule_with_comment_none(Rule::AST(Box::new(make_rule_with_comment_none(
     Rule::Paren(
       "<".to_string(),
       Box::new(make_rule_with_comment_none(Rule::Raw(42.to_string()))),
       ">".to_string(),
+      ParenConfig::default(),
     ),
   ))));
   let ok_str = format!("<42>");
@@ -171,7 +206,7 @@ fn check2() {
 }
 
 #[test]
-
+#[allow(clippy::useless_format)]
 fn check3() {
   let test = Test::AorB(Box::new(Test::A(42)));
   let ok_str = format!("<42>");
@@ -179,7 +214,7 @@ fn check3() {
 }
 
 #[test]
-
+#[allow(clippy::approx_constant, clippy::useless_format)]
 fn check4() {
   let test = Test::C(vec![
     Test::AorB(Box::new(Test::A(42))),
@@ -190,6 +225,7 @@ fn check4() {
 }
 
 #[test]
+#[allow(clippy::approx_constant, clippy::useless_format)]
 fn check5() {
   let test = Test::C(vec![
     Test::AorB(Box::new(Test::A(42))),
@@ -211,6 +247,7 @@ fn check5() {
 }
 
 #[test]
+#[allow(clippy::useless_format)]
 fn check6() {
   let test = Test::C(vec![
     Test::AorB(Box::new(Test::A(42))),
@@ -240,6 +277,7 @@ fn check6() {
 }
 
 #[test]
+#[allow(clippy::useless_format)]
 fn check7() {
   let test = Test::D(
     vec!["hoge".to_string(), "fuga".to_string()],
@@ -288,6 +326,7 @@ fuga
 }
 
 #[test]
+#[allow(clippy::useless_format)]
 fn check8() {
   let test = Test::D(
     vec!["hoge".to_string(), "fuga".to_string()],
@@ -349,7 +388,7 @@ fn check9() {
     rule: Rule::Column(vec![
       (
         RuleWithComment {
-          before_comments: vec!["comment".to_string()],
+          before_comments: vec![Comment::line("comment")],
           rule: Rule::Raw("a".to_string()),
           after_comment: None,
         },
@@ -367,13 +406,1290 @@ fn check9() {
         RuleWithComment {
           before_comments: vec![],
           rule: Rule::Raw("c".to_string()),
-          after_comment: Some("last".to_string()),
+          after_comment: Some(Comment::line("last")),
         },
         ColumnConfig::default(),
       ),
     ]),
-    after_comment: Some("last2".to_string()),
+    after_comment: Some(Comment::line("last2")),
   };
   let code = code_format(&make_ctx(), &rule);
   assert_eq!("// comment\na b c // last\n// last2".to_string(), code)
 }
+
+#[test]
+fn check10() {
+  let default_cc = ColumnConfig::default();
+  let rule = make_rule_with_comment_none(Rule::Column(vec![
+    (
+      make_rule_with_comment_none(Rule::Raw("let".to_string())),
+      default_cc.set_is_break(Some(false)),
+    ),
+    (
+      make_rule_with_comment_none(Rule::Raw("result".to_string())),
+      default_cc.set_is_break(Some(false)),
+    ),
+    (
+      make_rule_with_comment_none(Rule::Raw("=".to_string())),
+      default_cc.clone(),
+    ),
+    (
+      make_rule_with_comment_none(Rule::Raw("compute".to_string())),
+      default_cc.set_is_break(Some(false)).set_space_size(0),
+    ),
+    (
+      make_rule_with_comment_none(Rule::Raw("(alpha,".to_string())),
+      default_cc.clone(),
+    ),
+    (
+      make_rule_with_comment_none(Rule::Raw("beta,".to_string())),
+      default_cc.clone(),
+    ),
+    (
+      make_rule_with_comment_none(Rule::Raw("gamma)".to_string())),
+      default_cc.clone(),
+    ),
+  ]));
+  let ctx = Context {
+    line_width: 10,
+    column_layout: ColumnLayout::Optimal,
+    ..make_ctx()
+  };
+  assert_eq!(
+    "let result =\ncompute(alpha,\nbeta,\ngamma)".to_string(),
+    code_format(&ctx, &rule)
+  );
+  let ctx = Context {
+    line_width: 20,
+    column_layout: ColumnLayout::Optimal,
+    ..make_ctx()
+  };
+  assert_eq!(
+    "let result =\ncompute(alpha, beta,\ngamma)".to_string(),
+    code_format(&ctx, &rule)
+  );
+}
+
+#[test]
+fn check11() {
+  let rule = make_rule_with_comment_none(Rule::Paren(
+    "[".to_string(),
+    Box::new(make_rule_with_comment_none(Rule::List(
+      ",".to_string(),
+      vec!["変数名", "関数名", "型の名前", "定数"]
+        .into_iter()
+        .map(|s| make_rule_with_comment_none(Rule::Raw(s.to_string())))
+        .collect(),
+      ListConfig::default(),
+    ))),
+    "]".to_string(),
+    ParenConfig::default(),
+  ));
+  // バイト数では35を超えるが、表示幅では超えない
+  assert_eq!(
+    "[変数名, 関数名, 型の名前, 定数]".to_string(),
+    code_format(&make_ctx(), &rule)
+  );
+  let ctx = Context {
+    line_width: 28,
+    ..make_ctx()
+  };
+  assert_eq!(
+    "[\n  変数名,\n  関数名,\n  型の名前,\n  定数\n]".to_string(),
+    code_format(&ctx, &rule)
+  );
+}
+
+#[test]
+#[allow(clippy::approx_constant, clippy::useless_format)]
+fn check12() {
+  let test = Test::C(vec![
+    Test::AorB(Box::new(Test::A(42))),
+    Test::C(vec![Test::A(42), Test::B(3.14), Test::B(3.141)]),
+    Test::AorB(Box::new(Test::A(3333333))),
+    Test::A(3333333),
+    Test::AorB(Box::new(Test::A(3333333))),
+  ]);
+  let ctx = Context {
+    indent_style: IndentStyle::Tab { width: 2 },
+    ..make_ctx()
+  };
+  let ok_str = format!(
+    "[
+\t<42>,
+\t[42, (3.14), (3.141)],
+\t<3333333>,
+\t3333333,
+\t<3333333>
+]"
+  );
+  assert_eq!(ok_str, code_format(&ctx, &test.to_rule()));
+  // タブ文字の幅が大きいと入れ子になったリストが一行に収まらなくなる
+  let ctx = Context {
+    indent_style: IndentStyle::Tab { width: 8 },
+    ..make_ctx()
+  };
+  let ok_str = format!(
+    "[
+\t<42>,
+\t[
+\t\t42,
+\t\t(3.14),
+\t\t(3.141)
+\t],
+\t<3333333>,
+\t3333333,
+\t<3333333>
+]"
+  );
+  assert_eq!(ok_str, code_format(&ctx, &test.to_rule()));
+}
+
+fn make_raw(s: &str) -> RuleWithComment {
+  make_rule_with_comment_none(Rule::Raw(s.to_string()))
+}
+
+fn make_list(lst: Vec<RuleWithComment>, config: ListConfig) -> RuleWithComment {
+  make_rule_with_comment_none(Rule::Paren(
+    "[".to_string(),
+    Box::new(make_rule_with_comment_none(Rule::List(
+      ",".to_string(),
+      lst,
+      config,
+    ))),
+    "]".to_string(),
+    ParenConfig::default(),
+  ))
+}
+
+#[test]
+fn check13() {
+  let short = [Test::A(1), Test::A(2), Test::A(3)]
+    .iter()
+    .map(|t| t.to_rule())
+    .collect::<Vec<_>>();
+  let long = [
+    Test::A(1111111111),
+    Test::A(2222222222),
+    Test::A(3333333333),
+    Test::A(4444444444),
+  ]
+  .iter()
+  .map(|t| t.to_rule())
+  .collect::<Vec<_>>();
+  let multiline = ListConfig::default().set_trailing_separator(TrailingSeparator::Multiline);
+  let always = ListConfig::default().set_trailing_separator(TrailingSeparator::Always);
+  assert_eq!(
+    "[1, 2, 3]".to_string(),
+    code_format(&make_ctx(), &make_list(short.clone(), multiline.clone()))
+  );
+  assert_eq!(
+    "[1, 2, 3,]".to_string(),
+    code_format(&make_ctx(), &make_list(short, always))
+  );
+  assert_eq!(
+    "[\n  1111111111,\n  2222222222,\n  3333333333,\n  4444444444,\n]".to_string(),
+    code_format(&make_ctx(), &make_list(long.clone(), multiline.clone()))
+  );
+  // 要素直後のコメントの前に区切り文字が入る
+  let mut long_with_comment = long;
+  long_with_comment[3] = Test::AWithComment(vec![], 4444444444, Some("last".to_string())).to_rule();
+  assert_eq!(
+    "[\n  1111111111,\n  2222222222,\n  3333333333,\n  4444444444, // last\n]".to_string(),
+    code_format(
+      &make_ctx(),
+      &make_list(long_with_comment.clone(), multiline)
+    )
+  );
+  assert_eq!(
+    "[\n  1111111111,\n  2222222222,\n  3333333333,\n  4444444444 // last\n]".to_string(),
+    code_format(
+      &make_ctx(),
+      &make_list(long_with_comment, ListConfig::default())
+    )
+  );
+}
+
+#[test]
+#[allow(clippy::useless_format)]
+fn check14() {
+  let leading = ListConfig::default()
+    .set_separator_position(SeparatorPosition::Leading)
+    .set_trailing_separator(TrailingSeparator::Always);
+  let short = [Test::A(1), Test::A(2), Test::A(3)]
+    .iter()
+    .map(|t| t.to_rule())
+    .collect::<Vec<_>>();
+  assert_eq!(
+    "[1, 2, 3]".to_string(),
+    code_format(&make_ctx(), &make_list(short, leading.clone()))
+  );
+  let long = [
+    Test::A(1111111111),
+    Test::AWithComment(
+      vec!["hoge".to_string()],
+      2222222222,
+      Some("fuga".to_string()),
+    ),
+    Test::C(vec![
+      Test::A(3333333333),
+      Test::A(4444444444),
+      Test::A(5555555555),
+    ]),
+    Test::AWithComment(vec![], 6666666666, Some("last".to_string())),
+  ]
+  .iter()
+  .map(|t| t.to_rule())
+  .collect::<Vec<_>>();
+  let ok_str = format!(
+    "[
+  1111111111
+  // hoge
+  , 2222222222 // fuga
+  , [
+    3333333333,
+    4444444444,
+    5555555555
+  ]
+  , 6666666666 // last
+]"
+  );
+  assert_eq!(ok_str, code_format(&make_ctx(), &make_list(long, leading)));
+}
+
+#[test]
+fn check15() {
+  let short = [Test::A(1), Test::A(2), Test::A(3)]
+    .iter()
+    .map(|t| t.to_rule())
+    .collect::<Vec<_>>();
+  let make_list = |join: &str, config: ListConfig| {
+    make_rule_with_comment_none(Rule::List(join.to_string(), short.clone(), config))
+  };
+  let no_space = ListConfig::default().set_space_after_separator(0);
+  assert_eq!(
+    "1|2|3".to_string(),
+    code_format(&make_ctx(), &make_list("|", no_space.clone()))
+  );
+  let both = ListConfig::default().set_space_before_separator(1);
+  assert_eq!(
+    "1 . 2 . 3".to_string(),
+    code_format(&make_ctx(), &make_list(".", both.clone()))
+  );
+  let before = no_space.set_space_before_separator(1);
+  assert_eq!(
+    "1 ;2 ;3".to_string(),
+    code_format(&make_ctx(), &make_list(";", before.clone()))
+  );
+  let ctx = Context {
+    line_width: 4,
+    ..make_ctx()
+  };
+  assert_eq!(
+    "  1 .\n  2 .\n  3".to_string(),
+    code_format(&ctx, &make_list(".", both))
+  );
+  assert_eq!(
+    "  1\n  ;2\n  ;3".to_string(),
+    code_format(
+      &ctx,
+      &make_list(
+        ";",
+        before.set_separator_position(SeparatorPosition::Leading)
+      )
+    )
+  );
+}
+
+#[test]
+#[allow(clippy::useless_format)]
+fn check16() {
+  let mut lst = (100..118).map(Test::A).collect::<Vec<_>>();
+  lst[7] = Test::AWithComment(vec![], 107, Some("fuga".to_string()));
+  lst[12] = Test::AWithComment(vec!["hoge".to_string()], 112, None);
+  let lst = lst.iter().map(|t| t.to_rule()).collect::<Vec<_>>();
+  let fill = ListConfig::default().set_layout(ListLayout::Fill);
+  let ok_str = format!(
+    "[
+  100, 101, 102, 103, 104, 105,
+  106, 107, // fuga
+  108, 109, 110, 111,
+  // hoge
+  112, 113, 114, 115, 116, 117
+]"
+  );
+  assert_eq!(
+    ok_str,
+    code_format(&make_ctx(), &make_list(lst.clone(), fill.clone()))
+  );
+  let ok_str = format!(
+    "[
+  100, 101, 102, 103, 104, 105
+  , 106, 107 // fuga
+  , 108, 109, 110, 111
+  // hoge
+  , 112, 113, 114, 115, 116, 117
+]"
+  );
+  assert_eq!(
+    ok_str,
+    code_format(
+      &make_ctx(),
+      &make_list(lst, fill.set_separator_position(SeparatorPosition::Leading))
+    )
+  );
+}
+
+fn make_assign(name: &str, value: &str) -> RuleWithComment {
+  let default_cc = ColumnConfig::default();
+  make_rule_with_comment_none(Rule::Column(vec![
+    (
+      make_rule_with_comment_none(Rule::Raw("let".to_string())),
+      default_cc.set_is_break(Some(false)),
+    ),
+    (
+      make_rule_with_comment_none(Rule::Raw(name.to_string())),
+      default_cc.set_is_break(Some(false)),
+    ),
+    (
+      make_rule_with_comment_none(Rule::Raw("=".to_string())),
+      default_cc.set_is_aligned(true),
+    ),
+    (
+      make_rule_with_comment_none(Rule::Raw(format!("{value};"))),
+      default_cc.clone(),
+    ),
+  ]))
+}
+
+#[test]
+fn check17() {
+  let mut rows = vec![
+    make_assign("a", "1"),
+    make_assign("bcd", "22"),
+    make_assign("変数", "333"),
+  ];
+  rows[1].after_comment = Some(Comment::line("comment"));
+  let rule = make_rule_with_comment_none(Rule::Table(rows.clone()));
+  assert_eq!(
+    "let a    = 1;\nlet bcd  = 22; // comment\nlet 変数 = 333;".to_string(),
+    code_format(&make_ctx(), &rule)
+  );
+  // 揃えると長さの制限を超えてしまう行は揃えない
+  rows.push(make_assign("long_long_long_long_name", "1"));
+  rows.push(make_rule_with_comment(
+    vec!["not column".to_string()],
+    Rule::Raw("return;".to_string()),
+    None,
+  ));
+  rows.push(make_assign("x", "1234567890"));
+  let rule = make_rule_with_comment_none(Rule::Table(rows));
+  assert_eq!(
+    "let a                        = 1;
+let bcd                      = 22; // comment
+let 変数                     = 333;
+let long_long_long_long_name = 1;
+// not column
+return;
+let x = 1234567890;"
+      .to_string(),
+    code_format(&make_ctx(), &rule)
+  );
+}
+
+#[test]
+fn check18() {
+  let default_cc = ColumnConfig::default();
+  let rule = make_rule_with_comment_none(Rule::Column(vec![
+    (
+      make_rule_with_comment_none(Rule::Raw("call(".to_string())),
+      default_cc.set_is_break(Some(false)).set_space_size(0),
+    ),
+    (
+      make_rule_with_comment_none(Rule::Raw("alpha,".to_string())),
+      default_cc.clone(),
+    ),
+    (
+      make_rule_with_comment_none(Rule::Raw("beta,".to_string())),
+      default_cc.clone(),
+    ),
+    (
+      make_rule_with_comment_none(Rule::Raw("gamma)".to_string())),
+      default_cc.clone(),
+    ),
+  ]));
+  let ctx = Context {
+    column_layout: ColumnLayout::Consistent,
+    ..make_ctx()
+  };
+  assert_eq!(
+    "call(alpha, beta, gamma)".to_string(),
+    code_format(&ctx, &rule)
+  );
+  let ctx = Context {
+    line_width: 20,
+    column_layout: ColumnLayout::Consistent,
+    ..make_ctx()
+  };
+  assert_eq!(
+    "call(alpha,\nbeta,\ngamma)".to_string(),
+    code_format(&ctx, &rule)
+  );
+  let ctx = Context {
+    line_width: 20,
+    column_layout: ColumnLayout::Optimal,
+    ..make_ctx()
+  };
+  assert_eq!(
+    "call(alpha, beta,\ngamma)".to_string(),
+    code_format(&ctx, &rule)
+  );
+}
+
+#[test]
+fn check19() {
+  let default_cc = ColumnConfig::default();
+  let rule = make_rule_with_comment_none(Rule::Column(vec![
+    (
+      make_raw("call("),
+      default_cc.set_is_break(Some(false)).set_space_size(0),
+    ),
+    (make_raw("alpha,"), default_cc.clone()),
+    (make_raw("beta,"), default_cc.clone()),
+    (
+      make_raw("gamma"),
+      default_cc.set_is_break(Some(false)).set_space_size(0),
+    ),
+    (
+      make_rule_with_comment_none(Rule::IfBreak(
+        Box::new(make_raw(",")),
+        Box::new(make_raw("")),
+      )),
+      default_cc.set_is_break(Some(false)).set_space_size(0),
+    ),
+    (make_raw(")"), default_cc.clone()),
+  ]));
+  let ctx = Context {
+    column_layout: ColumnLayout::Consistent,
+    ..make_ctx()
+  };
+  assert_eq!(
+    "call(alpha, beta, gamma)".to_string(),
+    code_format(&ctx, &rule)
+  );
+  let ctx = Context {
+    line_width: 20,
+    column_layout: ColumnLayout::Consistent,
+    ..make_ctx()
+  };
+  assert_eq!(
+    "call(alpha,\nbeta,\ngamma,)".to_string(),
+    code_format(&ctx, &rule)
+  );
+  let lst = vec![
+    make_raw("1"),
+    make_raw("2"),
+    make_rule_with_comment_none(Rule::IfBreak(
+      Box::new(make_raw("more")),
+      Box::new(make_raw("...")),
+    )),
+  ];
+  assert_eq!(
+    "[1, 2, ...]".to_string(),
+    code_format(&make_ctx(), &make_list(lst.clone(), ListConfig::default()))
+  );
+  let ctx = Context {
+    line_width: 8,
+    ..make_ctx()
+  };
+  assert_eq!(
+    "[\n  1,\n  2,\n  more\n]".to_string(),
+    code_format(&ctx, &make_list(lst, ListConfig::default()))
+  );
+}
+
+#[test]
+fn check20() {
+  let body = make_rule_with_comment_none(Rule::Paren(
+    "{".to_string(),
+    Box::new(make_rule_with_comment_none(Rule::List(
+      ";".to_string(),
+      vec![
+        make_raw("let a = 1"),
+        make_raw("let b = 2"),
+        make_rule_with_comment_none(Rule::BlankLine(3)),
+        make_rule_with_comment_none(Rule::BlankLine(1)),
+        make_raw("a + b"),
+      ],
+      ListConfig::default().set_trailing_separator(TrailingSeparator::Multiline),
+    ))),
+    "}".to_string(),
+    ParenConfig::default(),
+  ));
+  assert_eq!(
+    "{\n  let a = 1;\n  let b = 2;\n\n  a + b;\n}".to_string(),
+    code_format(&make_ctx(), &body)
+  );
+  let ctx = Context {
+    max_blank_lines: 2,
+    ..make_ctx()
+  };
+  assert_eq!(
+    "{\n  let a = 1;\n  let b = 2;\n\n\n  a + b;\n}".to_string(),
+    code_format(&ctx, &body)
+  );
+  let lst = vec![
+    make_raw("1"),
+    make_raw("2"),
+    make_rule_with_comment_none(Rule::HardLine),
+    make_raw("3"),
+    make_raw("4"),
+  ];
+  assert_eq!(
+    "[\n  1, 2,\n  3, 4\n]".to_string(),
+    code_format(
+      &make_ctx(),
+      &make_list(lst, ListConfig::default().set_layout(ListLayout::Fill))
+    )
+  );
+  let default_cc = ColumnConfig::default();
+  let column = make_rule_with_comment_none(Rule::Column(vec![
+    (make_raw("a"), default_cc.clone()),
+    (
+      make_rule_with_comment_none(Rule::HardLine),
+      default_cc.clone(),
+    ),
+    (make_raw("b"), default_cc.clone()),
+  ]));
+  assert_eq!("a\nb".to_string(), code_format(&make_ctx(), &column));
+  let ctx = Context {
+    column_layout: ColumnLayout::Optimal,
+    ..make_ctx()
+  };
+  assert_eq!("a\nb".to_string(), code_format(&ctx, &column));
+}
+
+#[test]
+fn check21() {
+  let default_cc = ColumnConfig::default();
+  let statement = make_rule_with_comment_none(Rule::Column(vec![
+    (make_raw("let"), default_cc.clone()),
+    (make_raw("q"), default_cc.clone()),
+    (make_raw("="), default_cc.clone()),
+    (
+      make_rule_with_comment_none(Rule::Verbatim(
+        "r#\"SELECT *\n    FROM t\n\n\n\"#".to_string(),
+      )),
+      default_cc.set_is_break(Some(false)).set_space_size(0),
+    ),
+    (make_raw(";"), default_cc.clone()),
+  ]));
+  let body = make_rule_with_comment_none(Rule::Paren(
+    "{".to_string(),
+    Box::new(make_rule_with_comment_none(Rule::List(
+      "".to_string(),
+      vec![statement, make_raw("q")],
+      ListConfig::default().set_space_after_separator(0),
+    ))),
+    "}".to_string(),
+    ParenConfig::default(),
+  ));
+  let expected = "{\n  let q = r#\"SELECT *\n    FROM t\n\n\n\"#;\n  q\n}".to_string();
+  assert_eq!(expected, code_format(&make_ctx(), &body));
+  let ctx = Context {
+    column_layout: ColumnLayout::Optimal,
+    ..make_ctx()
+  };
+  assert_eq!(expected, code_format(&ctx, &body));
+}
+
+#[test]
+fn check22() {
+  let directive = |s: &str| make_rule_with_comment_none(Rule::Dedent(Box::new(make_raw(s))));
+  let block = |lst: Vec<RuleWithComment>| {
+    make_rule_with_comment_none(Rule::Paren(
+      "{".to_string(),
+      Box::new(make_rule_with_comment_none(Rule::List(
+        ",".to_string(),
+        lst,
+        ListConfig::default(),
+      ))),
+      "}".to_string(),
+      ParenConfig::default(),
+    ))
+  };
+  let inner = block(vec![
+    make_raw("a"),
+    directive("#ifdef DEBUG"),
+    make_raw("b"),
+    directive("#endif"),
+  ]);
+  let outer = block(vec![make_raw("x"), inner]);
+  assert_eq!(
+    "{\n  x,\n  {\n    a,\n#ifdef DEBUG\n    b\n#endif\n  }\n}".to_string(),
+    code_format(&make_ctx(), &outer)
+  );
+}
+
+#[test]
+fn check23() {
+  let call = |layout: ListLayout| {
+    make_rule_with_comment_none(Rule::Paren(
+      "(".to_string(),
+      Box::new(make_rule_with_comment_none(Rule::List(
+        "".to_string(),
+        vec![
+          make_raw("first-argument"),
+          make_raw("second-argument"),
+          make_raw("third-argument"),
+        ],
+        ListConfig::default().set_layout(layout),
+      ))),
+      ")".to_string(),
+      ParenConfig::default().set_is_aligned(true),
+    ))
+  };
+  assert_eq!(
+    "(first-argument\n second-argument\n third-argument)".to_string(),
+    code_format(&make_ctx(), &call(ListLayout::Vertical))
+  );
+  assert_eq!(
+    "(first-argument second-argument\n third-argument)".to_string(),
+    code_format(&make_ctx(), &call(ListLayout::Fill))
+  );
+  let block = make_rule_with_comment_none(Rule::Paren(
+    "{".to_string(),
+    Box::new(make_rule_with_comment_none(Rule::List(
+      ";".to_string(),
+      vec![call(ListLayout::Vertical)],
+      ListConfig::default(),
+    ))),
+    "}".to_string(),
+    ParenConfig::default(),
+  ));
+  assert_eq!(
+    "{\n  (first-argument\n   second-argument\n   third-argument)\n}".to_string(),
+    code_format(&make_ctx(), &block)
+  );
+}
+
+#[test]
+fn check24() {
+  let default_cc = ColumnConfig::default();
+  let expr = make_rule_with_comment_none(Rule::Column(
+    [
+      "let",
+      "total",
+      "=",
+      "first_value",
+      "+",
+      "second_value",
+      "+",
+      "third",
+    ]
+    .iter()
+    .map(|s| (make_raw(s), default_cc.clone()))
+    .collect(),
+  ));
+  let block = make_rule_with_comment_none(Rule::Paren(
+    "{".to_string(),
+    Box::new(make_rule_with_comment_none(Rule::List(
+      ";".to_string(),
+      vec![expr],
+      ListConfig::default(),
+    ))),
+    "}".to_string(),
+    ParenConfig::default(),
+  ));
+  let ctx = Context {
+    continuation_indent: 4,
+    ..make_ctx()
+  };
+  assert_eq!(
+    "{\n  let total = first_value +\n      second_value + third\n}".to_string(),
+    code_format(&ctx, &block)
+  );
+  let ctx = Context {
+    column_layout: ColumnLayout::Optimal,
+    ..ctx
+  };
+  assert_eq!(
+    "{\n  let total = first_value +\n      second_value + third\n}".to_string(),
+    code_format(&ctx, &block)
+  );
+}
+
+#[test]
+fn check25() {
+  let ctx = Context {
+    column_layout: ColumnLayout::Optimal,
+    ..make_ctx()
+  };
+  let default_cc = ColumnConfig::default();
+  let expr = |arg_cc: &ColumnConfig| {
+    make_rule_with_comment_none(Rule::Column(vec![
+      (make_raw("let"), default_cc.set_is_break(Some(false))),
+      (make_raw("value"), default_cc.set_is_break(Some(false))),
+      (make_raw("="), default_cc.clone()),
+      (make_raw("call(alpha,"), arg_cc.clone()),
+      (make_raw("beta,"), arg_cc.clone()),
+      (make_raw("gamma)"), default_cc.clone()),
+    ]))
+  };
+  assert_eq!(
+    "let value = call(alpha, beta,\ngamma)".to_string(),
+    code_format(&ctx, &expr(&default_cc))
+  );
+  assert_eq!(
+    "let value =\ncall(alpha, beta, gamma)".to_string(),
+    code_format(&ctx, &expr(&default_cc.set_penalty(10)))
+  );
+}
+
+#[test]
+fn check26() {
+  let default_cc = ColumnConfig::default();
+  let column = |second: RuleWithComment| {
+    make_rule_with_comment_none(Rule::Column(vec![
+      (make_raw("c"), default_cc.clone()),
+      (second, default_cc.clone()),
+      (make_raw("d"), default_cc.clone()),
+    ]))
+  };
+  let nested = |second: RuleWithComment| {
+    make_list(
+      vec![
+        make_raw("a"),
+        make_rule_with_comment_none(Rule::IfBreak(
+          Box::new(make_raw("broken")),
+          Box::new(make_raw("flat")),
+        )),
+        make_list(vec![make_raw("b"), column(second)], ListConfig::default()),
+      ],
+      ListConfig::default(),
+    )
+  };
+  assert_eq!(
+    "[a, flat, [b, c x d]]".to_string(),
+    code_format(&make_ctx(), &nested(make_raw("x")))
+  );
+  // 深い位置にある強制改行は、囲んでいるすべてのグループを複数行に分割する
+  assert_eq!(
+    "[\n  a,\n  broken,\n  [\n    b,\n    c\n    d\n  ]\n]".to_string(),
+    code_format(
+      &make_ctx(),
+      &nested(make_rule_with_comment_none(Rule::HardLine))
+    )
+  );
+  let commented =
+    make_rule_with_comment(vec!["note".to_string()], Rule::Raw("x".to_string()), None);
+  assert_eq!(
+    "[\n  a,\n  broken,\n  [\n    b,\n    c\n    // note\n    x d\n  ]\n]".to_string(),
+    code_format(&make_ctx(), &nested(commented))
+  );
+}
+
+#[test]
+fn check27() {
+  // 入れ子になったリストを毎回フォーマットし直すと、深さに対して指数的な回数だけ葉をフォーマットする
+  let depth = 40;
+  let mut rule_with_comment = make_rule_with_comment(
+    vec!["leaf".to_string()],
+    Rule::Raw("leaf".to_string()),
+    None,
+  );
+  for i in 0..depth {
+    rule_with_comment = make_list(
+      vec![make_raw(&format!("item{i}")), rule_with_comment],
+      ListConfig::default(),
+    );
+  }
+  // 葉の前のコメントをフォーマットした回数を数える
+  let count = std::cell::Cell::new(0);
+  let counted_comment_format = |s: String| {
+    count.set(count.get() + 1);
+    oneline_comment_format(s)
+  };
+  let ctx = Context {
+    oneline_comment_format: &counted_comment_format,
+    ..make_ctx()
+  };
+  let code = code_format(&ctx, &rule_with_comment);
+  assert_eq!(1, count.get());
+  let lines = code.lines().collect::<Vec<_>>();
+  assert_eq!(["[", "  item39,", "  ["], lines[..3]);
+  assert_eq!(3 * depth + 2, lines.len());
+  assert_eq!(" ".repeat(2 * depth) + "// leaf", lines[2 * depth]);
+  assert_eq!(" ".repeat(2 * depth) + "leaf", lines[2 * depth + 1]);
+}
+
+#[test]
+fn check28() {
+  // 左結合の長い式は木が非常に深くなるので、再帰呼び出しでフォーマットするとスタックがあふれる
+  let depth = 20000;
+  let default_cc = ColumnConfig::default();
+  let mut rule_with_comment = make_raw("x0");
+  for i in 1..depth {
+    rule_with_comment = make_rule_with_comment_none(Rule::Column(vec![
+      (rule_with_comment, default_cc.clone()),
+      (make_raw("+"), default_cc.clone()),
+      (make_raw(&format!("x{i}")), default_cc.clone()),
+    ]));
+  }
+  let code = code_format(&make_ctx(), &rule_with_comment);
+  let expected = (0..depth)
+    .map(|i| format!("x{i}"))
+    .collect::<Vec<_>>()
+    .join(" + ");
+  assert_eq!(expected, code.replace('\n', " "));
+  assert!(code.lines().all(|line| line.len() <= 35));
+  drop_deep(rule_with_comment);
+}
+
+#[test]
+fn check29() {
+  let rule_with_comment = make_list(
+    vec![
+      make_rule_with_comment(
+        vec!["first".to_string()],
+        Rule::Raw("alpha".to_string()),
+        None,
+      ),
+      make_raw("beta"),
+    ],
+    ListConfig::default(),
+  );
+  let expected = "[\n  // first\n  alpha,\n  beta\n]".to_string();
+  assert_eq!(expected, code_format(&make_ctx(), &rule_with_comment));
+  // 文字列を経由せずに書き出しても同じ結果になる
+  let mut bytes = vec![];
+  code_format_to_io(&make_ctx(), &rule_with_comment, &mut bytes).unwrap();
+  assert_eq!(expected, String::from_utf8(bytes).unwrap());
+  let mut code = String::from("let x = ");
+  code_format_to_fmt(&make_ctx(), &rule_with_comment, &mut code).unwrap();
+  assert_eq!(format!("let x = {expected}"), code);
+}
+
+#[test]
+fn check30() {
+  let rule_with_comment = make_list(
+    vec![
+      RuleWithComment {
+        before_comments: vec![Comment::doc("first"), Comment::doc("second")],
+        after_comment: Some(Comment::block("note")),
+        ..make_raw("a")
+      },
+      RuleWithComment {
+        // 一つだけでもブロックコメントのままにする
+        before_comments: vec![Comment::block("single")],
+        after_comment: Some(Comment::line("tail").set_placement(CommentPlacement::OwnLine)),
+        ..make_raw("b")
+      },
+      make_raw("c"),
+    ],
+    ListConfig::default(),
+  );
+  assert_eq!(
+    "[\n  /// first\n  /// second\n  a, /* note */\n  /*\n  single\n  */\n  b,\n  // tail\n  c\n]"
+      .to_string(),
+    code_format(&make_ctx(), &rule_with_comment)
+  );
+  // 独立した行に置くコメントがあると、一行に収まっても複数行に分割される
+  let rule_with_comment = make_list(
+    vec![
+      make_raw("x"),
+      RuleWithComment {
+        after_comment: Some(Comment::line("own").set_placement(CommentPlacement::OwnLine)),
+        ..make_raw("y")
+      },
+    ],
+    ListConfig::default(),
+  );
+  assert_eq!(
+    "[\n  x,\n  y\n  // own\n]".to_string(),
+    code_format(&make_ctx(), &rule_with_comment)
+  );
+}
+
+#[test]
+fn check31() {
+  let ctx = Context {
+    is_comment_reflowed: true,
+    ..make_ctx()
+  };
+  let text = [
+    "This comment is long enough that it must be wrapped https://example.com/a/very/long/path",
+    "```",
+    "let long_code_line = call(argument_one, argument_two);",
+    "```",
+  ]
+  .join("\n");
+  let rule_with_comment = make_list(
+    vec![
+      RuleWithComment {
+        before_comments: vec![Comment::line(&text)],
+        ..make_raw("x")
+      },
+      make_raw("y"),
+    ],
+    ListConfig::default(),
+  );
+  // コメント記号とインデントを除いた幅に収まるように折り返し、URLとコードブロックはそのまま残す
+  assert_eq!(
+    "[
+  // This comment is long enough
+  // that it must be wrapped
+  // https://example.com/a/very/long/path
+  // ```
+  // let long_code_line = call(argument_one, argument_two);
+  // ```
+  x,
+  y
+]"
+    .to_string(),
+    code_format(&ctx, &rule_with_comment)
+  );
+  // 幅は表示上の幅で数える
+  let ctx = Context {
+    line_width: 20,
+    ..ctx
+  };
+  let rule_with_comment = RuleWithComment {
+    before_comments: vec![Comment::line("全角の 単語は 幅を 二倍で 数える")],
+    ..make_raw("x")
+  };
+  assert_eq!(
+    "// 全角の 単語は\n// 幅を 二倍で\n// 数える\nx".to_string(),
+    code_format(&ctx, &rule_with_comment)
+  );
+}
+
+#[test]
+fn check32() {
+  let commented = |code: &str, comment: &str| {
+    make_rule_with_comment(
+      vec![],
+      Rule::Raw(code.to_string()),
+      Some(comment.to_string()),
+    )
+  };
+  let rule_with_comment = make_list(
+    vec![
+      commented("a", "first"),
+      commented("long_name", "second"),
+      make_raw("c"),
+      commented("e", "long comment text here ok"),
+      commented("ffffffffff", "x"),
+      commented("g", "y"),
+    ],
+    ListConfig::default().set_is_comment_aligned(true),
+  );
+  // コメントの無い行で区切られた範囲ごとに揃え、揃えると長すぎる行は揃えない
+  assert_eq!(
+    "[
+  a,         // first
+  long_name, // second
+  c,
+  e, // long comment text here ok
+  ffffffffff, // x
+  g           // y
+]"
+    .to_string(),
+    code_format(&make_ctx(), &rule_with_comment)
+  );
+}
+
+#[test]
+fn check33() {
+  let rule_with_comment = make_rule_with_comment_none(Rule::Paren(
+    "f(".to_string(),
+    Box::new(make_rule_with_comment_none(Rule::List(
+      ",".to_string(),
+      vec![
+        RuleWithComment {
+          before_comments: vec![Comment::block("retries")],
+          ..make_raw("3")
+        },
+        make_raw("x"),
+      ],
+      ListConfig::default().set_is_block_comment_inlined(true),
+    ))),
+    ")".to_string(),
+    ParenConfig::default(),
+  ));
+  // 一行のブロックコメントがあっても、収まれば一行にする
+  assert_eq!(
+    "f(/* retries */ 3, x)".to_string(),
+    code_format(&make_ctx(), &rule_with_comment)
+  );
+  // 複数行に分割しても、コメントは要素と同じ行に置く
+  let ctx = Context {
+    line_width: 15,
+    ..make_ctx()
+  };
+  assert_eq!(
+    "f(\n  /* retries */ 3,\n  x\n)".to_string(),
+    code_format(&ctx, &rule_with_comment)
+  );
+}
+
+#[test]
+fn check34() {
+  let list = || {
+    Box::new(make_rule_with_comment_none(Rule::List(
+      ",".to_string(),
+      vec![make_raw("a"), make_raw("b")],
+      ListConfig::default(),
+    )))
+  };
+  // 括弧に付けたコメントがあると、一行に収まっても複数行に分割される
+  let rule_with_comment = make_rule_with_comment_none(Rule::Paren(
+    "{".to_string(),
+    list(),
+    "}".to_string(),
+    ParenConfig::default()
+      .set_open_comment(Comment::line("section"))
+      .set_close_comment(Comment::line("end")),
+  ));
+  assert_eq!(
+    "{ // section\n  a,\n  b\n  // end\n}".to_string(),
+    code_format(&make_ctx(), &rule_with_comment)
+  );
+  let rule_with_comment = make_rule_with_comment_none(Rule::Paren(
+    "(".to_string(),
+    list(),
+    ")".to_string(),
+    ParenConfig::default()
+      .set_is_aligned(true)
+      .set_close_comment(Comment::line("end")),
+  ));
+  assert_eq!(
+    "(a,\n b\n // end\n)".to_string(),
+    code_format(&make_ctx(), &rule_with_comment)
+  );
+}
+
+#[test]
+fn check35() {
+  // 複数行になった`AST`の直後のコメントも、種類に従ってフォーマットする
+  let default_cc = ColumnConfig::default();
+  let rule_with_comment = RuleWithComment {
+    after_comment: Some(Comment::block("note")),
+    ..make_rule_with_comment_none(Rule::AST(Box::new(make_rule_with_comment_none(
+      Rule::Column(vec![
+        (make_raw("x"), default_cc.clone()),
+        (
+          make_rule_with_comment_none(Rule::HardLine),
+          default_cc.clone(),
+        ),
+        (make_raw("y"), default_cc.clone()),
+      ]),
+    ))))
+  };
+  assert_eq!(
+    "x\ny\n/* note */".to_string(),
+    code_format(&make_ctx(), &rule_with_comment)
+  );
+}
+
+#[test]
+fn check36() {
+  // 独立した行に置くコメントも、置かれる位置のインデントに合わせて折り返す
+  // 単語の間の空白はそのまま残す
+  let x = RuleWithComment {
+    after_comment: Some(
+      Comment::line("keep  two  spaces here").set_placement(CommentPlacement::OwnLine),
+    ),
+    ..make_raw("x")
+  };
+  let rule_with_comment = make_rule_with_comment_none(Rule::Paren(
+    "{".to_string(),
+    Box::new(make_rule_with_comment_none(Rule::List(
+      ",".to_string(),
+      vec![x, make_raw("y")],
+      ListConfig::default(),
+    ))),
+    "}".to_string(),
+    ParenConfig::default().set_close_comment(Comment::line("aaaa bbbb cccc dd")),
+  ));
+  let ctx = Context {
+    line_width: 20,
+    is_comment_reflowed: true,
+    ..make_ctx()
+  };
+  assert_eq!(
+    "{\n  x,\n  // keep  two\n  // spaces here\n  y\n  // aaaa bbbb cccc\n  // dd\n}".to_string(),
+    code_format(&ctx, &rule_with_comment)
+  );
+}
+
+#[test]
+fn check37() {
+  // 揃えた`Paren`の中身が直後のコメントで終わる場合は、閉じ括弧を次の行に置く
+  let rule_with_comment = make_rule_with_comment_none(Rule::Paren(
+    "(".to_string(),
+    Box::new(make_rule_with_comment_none(Rule::List(
+      ",".to_string(),
+      vec![
+        make_raw("a"),
+        make_rule_with_comment(vec![], Rule::Raw("b".to_string()), Some("c".to_string())),
+      ],
+      ListConfig::default(),
+    ))),
+    ")".to_string(),
+    ParenConfig::default().set_is_aligned(true),
+  ));
+  assert_eq!(
+    "(a,\n b // c\n)".to_string(),
+    code_format(&make_ctx(), &rule_with_comment)
+  );
+}
+
+#[test]
+fn check38() {
+  // `AST`の中の要素の直後のコメントも、囲んでいるリストを複数行に分割する
+  let commented = make_rule_with_comment(vec![], Rule::Raw("a".to_string()), Some("c".to_string()));
+  let rule_with_comment = make_list(
+    vec![
+      make_rule_with_comment_none(Rule::AST(Box::new(commented))),
+      make_raw("bbbbbbbb"),
+    ],
+    ListConfig::default(),
+  );
+  assert_eq!(
+    "[\n  a, // c\n  bbbbbbbb\n]".to_string(),
+    code_format(&make_ctx(), &rule_with_comment)
+  );
+}
+
+#[test]
+fn check39() {
+  // 左に入れ子になった`Column`でも継続行のインデントは一度だけ付け、空の行を出力しない
+  let default_cc = ColumnConfig::default();
+  let mut expr = make_raw("x0");
+  for i in 1..4 {
+    expr = make_rule_with_comment_none(Rule::Column(vec![
+      (expr, default_cc.clone()),
+      (make_raw("+"), default_cc.clone()),
+      (make_raw(&format!("x{i}")), default_cc.clone()),
+    ]));
+  }
+  let ctx = Context {
+    continuation_indent: 4,
+    line_width: 8,
+    ..make_ctx()
+  };
+  assert_eq!(
+    "x0 + x1\n    + x2\n    + x3".to_string(),
+    code_format(&ctx, &expr)
+  );
+  let ctx = Context {
+    column_layout: ColumnLayout::Optimal,
+    ..ctx
+  };
+  assert_eq!(
+    "x0 + x1\n    + x2\n    + x3".to_string(),
+    code_format(&ctx, &expr)
+  );
+  let column = make_rule_with_comment_none(Rule::Column(vec![
+    (make_raw("aaaaaaaaaaaa"), default_cc.clone()),
+    (make_raw("b"), default_cc.clone()),
+  ]));
+  let ctx = Context {
+    continuation_indent: 4,
+    line_width: 10,
+    ..make_ctx()
+  };
+  assert_eq!(
+    "aaaaaaaaaaaa\n    b".to_string(),
+    code_format(&ctx, &column)
+  );
+}
+
+#[test]
+fn check40() {
+  // 深く入れ子になったリストや前のコメントを持つ`AST`でも、子の行を何度も移し替えない
+  let depth = 20000;
+  let mut rule_with_comment = make_raw("x");
+  for i in 0..depth {
+    rule_with_comment = make_list(
+      vec![make_raw(&format!("i{i}")), rule_with_comment],
+      ListConfig::default(),
+    );
+  }
+  let ctx = Context {
+    tab_spaces: 0,
+    ..make_ctx()
+  };
+  let code = code_format(&ctx, &rule_with_comment);
+  let expected = (0..depth)
+    .rev()
+    .map(|i| format!("[i{i},"))
+    .collect::<String>()
+    + "x"
+    + &"]".repeat(depth);
+  assert_eq!(expected, code.replace(['\n', ' '], ""));
+  assert!(code.lines().all(|line| line.len() <= 35));
+  drop_deep(rule_with_comment);
+  let mut rule_with_comment = make_raw("x");
+  for i in 0..depth {
+    rule_with_comment = make_rule_with_comment(
+      vec![format!("c{i}")],
+      Rule::AST(Box::new(rule_with_comment)),
+      None,
+    );
+  }
+  let expected = (0..depth)
+    .rev()
+    .map(|i| format!("// c{i}\n"))
+    .collect::<String>()
+    + "x";
+  assert_eq!(expected, code_format(&make_ctx(), &rule_with_comment));
+  drop_deep(rule_with_comment);
+}
+
+#[test]
+fn check41() {
+  // 最上位の`List`は要素ごとにフォーマットし、確定した行から書き出す
+  struct LogWriter<'a>(&'a std::cell::RefCell<Vec<String>>);
+  impl std::fmt::Write for LogWriter<'_> {
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+      if s != "\n" {
+        self.0.borrow_mut().push(format!("write {s}"))
+      }
+      Ok(())
+    }
+  }
+  let log = std::cell::RefCell::new(vec![]);
+  let comment_format = |s: String| {
+    log.borrow_mut().push(format!("format {s}"));
+    format!("// {s}")
+  };
+  let ctx = Context {
+    oneline_comment_format: &comment_format,
+    ..make_ctx()
+  };
+  let rule_with_comment = make_rule_with_comment_none(Rule::List(
+    ";".to_string(),
+    ["a", "b", "c"]
+      .iter()
+      .map(|s| make_rule_with_comment(vec![s.to_string()], Rule::Raw(s.to_string()), None))
+      .collect(),
+    ListConfig::default(),
+  ));
+  code_format_to_fmt(&ctx, &rule_with_comment, &mut LogWriter(&log)).unwrap();
+  assert_eq!(
+    vec![
+      "format a",
+      "format b",
+      "write   // a",
+      "format c",
+      "write   a;",
+      "write   // b",
+      "write   b;",
+      "write   // c",
+      "write   c",
+    ],
+    *log.borrow()
+  );
+}
@@ -0,0 +1,28 @@
+extern crate code_format;
+
+use code_format::width::{char_width, str_width};
+
+#[test]
+fn check_char_width() {
+  assert_eq!(1, char_width('a', 1));
+  assert_eq!(2, char_width('あ', 1));
+  assert_eq!(2, char_width('漢', 1));
+  assert_eq!(2, char_width('Ａ', 1));
+  assert_eq!(1, char_width('ｱ', 1));
+  assert_eq!(0, char_width('\u{0301}', 1));
+  assert_eq!(0, char_width('\u{200B}', 1));
+  assert_eq!(2, char_width('😀', 1));
+  assert_eq!(1, char_width('α', 1));
+  assert_eq!(2, char_width('α', 2));
+}
+
+#[test]
+fn check_str_width() {
+  assert_eq!(0, str_width("", 1));
+  assert_eq!(5, str_width("hello", 1));
+  assert_eq!(12, str_width("日本語の名前", 1));
+  assert_eq!(1, str_width("e\u{0301}", 1));
+  // 家族の絵文字は一つの絵文字として表示される
+  assert_eq!(2, str_width("👨\u{200D}👩\u{200D}👧", 1));
+  assert_eq!(6, str_width("±α→", 2));
+}